@@ -0,0 +1,188 @@
+/// This file holds how a session behaves in its channel, which is the same whatever engine is
+/// behind it
+use rand::Rng;
+use std::{fmt, time::Duration};
+
+/// Which lines a session replies to
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// Lines starting with the prefix, which is left out of the log
+    Prefix(String),
+    /// Lines that mention the bot
+    Mention,
+    /// Lines replying to one of the bot's messages
+    Reply,
+    /// Every line
+    All,
+    /// Every line, with the given chance of answering it
+    ChimeIn(f64),
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Trigger::Prefix(String::from(">"))
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Prefix(prefix) => write!(f, "prefix `{}`", prefix),
+            Trigger::Mention => write!(f, "mention"),
+            Trigger::Reply => write!(f, "reply"),
+            Trigger::All => write!(f, "all"),
+            Trigger::ChimeIn(rate) => write!(f, "chime in {}%", rate * 100.0),
+        }
+    }
+}
+
+/// How long a session waits for the channel to go quiet before replying, all in milliseconds
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Timing {
+    /// Wait after the line that starts a reply
+    pub first_line_ms: u64,
+    /// Wait after every line that arrives while we're already waiting
+    pub follow_up_ms: u64,
+    /// Longest wait from the first line, so a busy channel still gets an answer
+    pub max_wait_ms: u64,
+    /// Shortest time between the end of one reply and the start of the next
+    pub reply_gap_ms: u64,
+}
+
+/// One of the waits in [`Timing`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delay {
+    FirstLine,
+    FollowUp,
+    MaxWait,
+    ReplyGap,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            first_line_ms: 2_500,
+            follow_up_ms: 1_500,
+            max_wait_ms: 15_000,
+            reply_gap_ms: 0,
+        }
+    }
+}
+
+impl Timing {
+    pub fn set(&mut self, delay: Delay, millis: u64) {
+        match delay {
+            Delay::FirstLine => self.first_line_ms = millis,
+            Delay::FollowUp => self.follow_up_ms = millis,
+            Delay::MaxWait => self.max_wait_ms = millis,
+            Delay::ReplyGap => self.reply_gap_ms = millis,
+        }
+    }
+
+    pub fn first_line(&self) -> Duration {
+        Duration::from_millis(self.first_line_ms)
+    }
+
+    pub fn follow_up(&self) -> Duration {
+        Duration::from_millis(self.follow_up_ms)
+    }
+
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+
+    pub fn reply_gap(&self) -> Duration {
+        Duration::from_millis(self.reply_gap_ms)
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ms, {}ms per line, {}ms at most",
+            self.first_line_ms, self.follow_up_ms, self.max_wait_ms
+        )?;
+        if self.reply_gap_ms > 0 {
+            write!(f, ", {}ms between replies", self.reply_gap_ms)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    pub trigger: Trigger,
+    /// Record lines that don't trigger a reply too, so replies have them as context
+    pub listen: bool,
+    pub timing: Timing,
+}
+
+impl ChannelSettings {
+    /// Whether a line should get a reply. Lines with a different prefix never do, even when
+    /// they're recorded
+    pub fn is_triggered_by(&self, content: &str, mentions_bot: bool, replies_to_bot: bool) -> bool {
+        match &self.trigger {
+            Trigger::Prefix(prefix) => content.starts_with(prefix.as_str()),
+            Trigger::Mention => mentions_bot,
+            Trigger::Reply => replies_to_bot,
+            Trigger::All => true,
+            Trigger::ChimeIn(rate) => rand::thread_rng().gen_bool(rate.max(0.0).min(1.0)),
+        }
+    }
+
+    /// The part of a line that goes into the log, if it goes in at all
+    pub fn line_text<'a>(&self, content: &'a str) -> Option<&'a str> {
+        match &self.trigger {
+            Trigger::Prefix(prefix) if content.starts_with(prefix.as_str()) => {
+                Some(content.trim_start_matches(prefix.as_str()))
+            }
+            Trigger::Prefix(_) if !self.listen => None,
+            _ => Some(content),
+        }
+    }
+}
+
+impl fmt::Display for ChannelSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.trigger)?;
+        if self.listen {
+            write!(f, ", listening")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixed_lines_are_stripped_and_others_only_recorded_when_listening() {
+        let mut settings = ChannelSettings {
+            trigger: Trigger::Prefix(String::from("dot,")),
+            listen: false,
+            ..ChannelSettings::default()
+        };
+        assert!(settings.is_triggered_by("dot, hello", false, false));
+        assert!(!settings.is_triggered_by("hello", true, true));
+        assert_eq!(settings.line_text("dot, hello"), Some(" hello"));
+        assert_eq!(settings.line_text("hello"), None);
+
+        settings.listen = true;
+        assert_eq!(settings.line_text("hello"), Some("hello"));
+
+        settings.trigger = Trigger::Mention;
+        assert!(settings.is_triggered_by("@Dorothy hi", true, false));
+        assert!(!settings.is_triggered_by("hi", false, true));
+        assert_eq!(settings.line_text("dot, hello"), Some("dot, hello"));
+
+        settings.trigger = Trigger::ChimeIn(0.0);
+        assert!(!settings.is_triggered_by("hi", true, true));
+        settings.trigger = Trigger::ChimeIn(1.0);
+        assert!(settings.is_triggered_by("hi", false, false));
+    }
+}
@@ -1,13 +1,24 @@
-use crate::MessageSessionHandler;
+use crate::{
+    channel::{Delay, Trigger},
+    config::DirectMessageConfig,
+    permissions::{Capability, Grantee, Requirement},
+    platform::{ChatPlatform, Discord},
+    usage::{Date, Period},
+    ChatTarget, MessageSessionHandler, SharedSession, Tuning,
+};
 use serenity::{
     framework::standard::{
         macros::{command, group, hook},
-        ArgError, Args, CommandResult,
+        ArgError, Args, CommandResult, Delimiter,
+    },
+    model::{
+        channel::Message,
+        id::{GuildId, RoleId, UserId},
     },
-    model::channel::Message,
-    prelude::Context,
+    prelude::{Context, RwLock},
+    utils::{parse_role, parse_username},
 };
-use std::sync::Arc;
+use std::{ops::RangeInclusive, sync::Arc};
 
 #[derive(Debug)]
 pub struct StringError(String);
@@ -30,6 +41,12 @@ impl From<&str> for StringError {
     }
 }
 
+impl From<String> for StringError {
+    fn from(data: String) -> Self {
+        StringError(data)
+    }
+}
+
 impl<T> From<ArgError<T>> for StringError
 where
     T: std::fmt::Display,
@@ -49,120 +66,567 @@ impl From<serenity::Error> for StringError {
     }
 }
 
+async fn get_storage(ctx: &Context) -> Result<Arc<crate::storage::Storage>, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::StorageKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get session storage"))
+}
+
+async fn get_dispatcher(ctx: &Context) -> Result<Arc<crate::dispatch::Dispatcher>, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::DispatcherKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get the message dispatcher"))
+}
+
+async fn get_session_map(ctx: &Context) -> Result<crate::ThreadsafeSessionMap, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::SessionMapKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get read copy of session map"))
+}
+
+/// The message's chat target and the session in it
+async fn get_message_session(
+    ctx: &Context,
+    msg: &Message,
+) -> Result<(ChatTarget, SharedSession), StringError> {
+    let chat_target = crate::get_chat_target_from_message(msg);
+    let session_map = get_session_map(ctx).await?;
+    let session = crate::get_session(&session_map, &chat_target)
+        .await
+        .ok_or_else(|| StringError::from("Chat target does not has a session"))?;
+    Ok((chat_target, session))
+}
+
 #[group]
+#[prefixes("set")]
+#[commands(
+    temperature,
+    top_p,
+    presence_penalty,
+    frequency_penalty,
+    max_tokens,
+    engine,
+    context,
+    retries,
+    mode,
+    streaming,
+    summarizer,
+    trigger,
+    listen,
+    first_delay,
+    follow_up_delay,
+    max_delay,
+    reply_gap
+)]
 pub struct ConversationTuning;
 
 #[group]
-#[only_in(guilds)]
-#[commands(enable, disable, reset, info)]
+#[commands(
+    enable,
+    disable,
+    reset,
+    undo,
+    retry,
+    forget,
+    info,
+    usage,
+    grant,
+    revoke,
+    permissions
+)]
 pub struct Admin;
 
-#[command]
+#[group]
 #[owners_only]
+#[commands(allow, deny)]
+pub struct Owner;
+
+#[command]
 /// enable will create a session for the target for the message, if it exists
 async fn enable(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let chat_target = crate::get_chat_target_from_message(msg)
-        .ok_or_else(|| StringError::from("Could not create chat target for message"))?;
-    let data_read = ctx.data.read().await;
-    let session_map = data_read
-        .get::<crate::SessionMapKey>()
-        .ok_or_else(|| StringError::from("Could not get read copy of session map"))?;
-    let session_map = Arc::clone(&session_map);
-    drop(data_read);
-    let session_map_read = session_map.read().await;
-    if session_map_read.contains_key(&chat_target) {
-        Err(StringError::from("Chat target already has a session").into())
-    } else {
-        drop(session_map_read);
-        let mut session_map_write = session_map.write().await;
-        let session_name: String = args.single()?;
-        let session = match &*session_name.to_lowercase() {
-            "gpt2" => crate::gpt2::GPT2MessageHandler::enable(ctx, msg, args).await?,
-            "gpt3" => {
-                if msg.guild_id.is_none() || msg.guild_id.unwrap() != 394151608822398976 {
-                    return Err(StringError::from("GPT3 is not enabled for this guild").into());
-                }
-                crate::gpt3::GPT3MessageHandler::enable(ctx, msg, args).await?
-            }
-            _ => {
-                return Err(StringError(format!(
-                    "No complection engine found for {}",
-                    session_name,
-                ))
-                .into());
-            }
-        };
-        session_map_write.insert(chat_target, session);
-        Ok(())
+    let chat_target = crate::get_chat_target_from_message(msg);
+    let session_map = get_session_map(ctx).await?;
+    if session_map.read().await.contains_key(&chat_target) {
+        return Err(StringError::from("Chat target already has a session").into());
     }
+    let config = get_config(ctx).await?;
+    let config_read = config.read().await;
+    let rules = config_read
+        .rules(chat_target.guild_id)
+        .cloned()
+        .ok_or_else(|| StringError::from("No engines are enabled here"))?;
+    let mut session_name = args.single::<String>()?.to_lowercase();
+    if session_name == "default" {
+        session_name = rules
+            .default_engine
+            .clone()
+            .ok_or_else(|| StringError::from("There is no default engine here"))?;
+    }
+    if !rules.allows(&session_name) {
+        return Err(format!("{} is not enabled here", session_name).into());
+    }
+    // DM sessions enabled without a persona get the one the owners configured
+    let args = match &config_read.direct_messages {
+        Some(dms) if chat_target.guild_id.is_none() && args.is_empty() => {
+            Args::new(&direct_message_persona(dms), &[Delimiter::Single(' ')])
+        }
+        _ => args,
+    };
+    drop(config_read);
+    let mut session = match &*session_name.to_lowercase() {
+        "gpt2" => crate::gpt2::GPT2MessageHandler::enable(ctx, msg, args).await?,
+        "gpt3" => crate::gpt3::GPT3MessageHandler::enable(ctx, msg, args).await?,
+        _ => {
+            return Err(
+                StringError(format!("No complection engine found for {}", session_name,)).into(),
+            );
+        }
+    };
+    session.apply_limits(&rules);
+    // setting the session up talks to Discord, so the map is only locked to add it
+    let mut session_map_write = session_map.write().await;
+    if session_map_write.contains_key(&chat_target) {
+        return Err(StringError::from("Chat target already has a session").into());
+    }
+    if let Err(why) = get_storage(ctx)
+        .await?
+        .save_session(&chat_target, session.save())
+    {
+        eprintln!("Failed to save session: {}", &why);
+    }
+    session_map_write.insert(chat_target, session.into_shared());
+    Ok(())
+}
+
+/// Spells out the configured DM persona as the arguments `enable` would otherwise be given
+fn direct_message_persona(dms: &DirectMessageConfig) -> String {
+    let ai_name = dms
+        .ai_name
+        .as_ref()
+        .map_or_else(|| String::from("_"), |ai_name| format!("\"{}\"", ai_name));
+    format!(
+        "default conversation {} {}",
+        ai_name,
+        dms.context.as_deref().unwrap_or_default()
+    )
 }
 
 #[command]
-#[owners_only]
 /// reset clears the mssage log
 async fn reset(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let chat_target = crate::get_chat_target_from_message(msg)
-        .ok_or_else(|| StringError::from("Could not create chat target for message"))?;
-    let data_read = ctx.data.read().await;
-    let session_map = data_read
-        .get::<crate::SessionMapKey>()
-        .ok_or_else(|| StringError::from("Could not get read copy of session map"))?;
-    let session_map = Arc::clone(&session_map);
-    drop(data_read);
-    let session_map_read = session_map.read().await;
-    if session_map_read.contains_key(&chat_target) {
-        drop(session_map_read);
-        let mut session_map_write = session_map.write().await;
-        if let Some(session) = session_map_write.get_mut(&chat_target) {
-            session.reset(ctx, msg, args).await
-        } else {
-            Err(StringError::from("Chat target does not has a session").into())
-        }
-    } else {
-        Err(StringError::from("Chat target does not has a session").into())
+    let (chat_target, handle) = get_message_session(ctx, msg).await?;
+    let dispatcher = get_dispatcher(ctx).await?;
+    // a reply still on its way would land in the fresh log
+    dispatcher.cancel(&chat_target).await;
+    let mut session = handle.lock().await;
+    session.reset(ctx, msg, args).await?;
+    dispatcher.save_later(&chat_target);
+    Ok(())
+}
+
+fn discord_platform(ctx: &Context) -> Arc<dyn ChatPlatform> {
+    Arc::new(Discord::new(Arc::clone(&ctx.cache), Arc::clone(&ctx.http)))
+}
+
+#[command]
+/// undo takes back the last reply and deletes its message
+async fn undo(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let chat_target = crate::get_chat_target_from_message(msg);
+    let dispatcher = get_dispatcher(ctx).await?;
+    if !dispatcher.should_respond_to_target(&chat_target).await {
+        return Err(StringError::from("Chat target does not has a session").into());
+    }
+    if dispatcher
+        .undo(&*discord_platform(ctx), &chat_target)
+        .await
+        .is_none()
+    {
+        return Err(StringError::from("There is no reply to take back").into());
     }
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+#[command]
+/// retry takes back the last reply and generates a new one from the same conversation
+async fn retry(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let chat_target = crate::get_chat_target_from_message(msg);
+    let dispatcher = get_dispatcher(ctx).await?;
+    if !dispatcher.should_respond_to_target(&chat_target).await {
+        return Err(StringError::from("Chat target does not has a session").into());
+    }
+    let retried = dispatcher
+        .retry(discord_platform(ctx), &chat_target, msg.author.id)
+        .await?;
+    if !retried {
+        return Err(StringError::from("There is no reply to retry").into());
+    }
+    Ok(())
+}
+
+#[command]
+/// forget drops the last N lines of the conversation, both people's and the bot's
+async fn forget(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let chat_target = crate::get_chat_target_from_message(msg);
+    let count = single_in_range(&mut args, "count", 1..=100)?;
+    let dispatcher = get_dispatcher(ctx).await?;
+    if !dispatcher.should_respond_to_target(&chat_target).await {
+        return Err(StringError::from("Chat target does not has a session").into());
+    }
+    let forgotten = dispatcher.forget(&chat_target, count).await;
+    msg.reply(&ctx, format!("Forgot {} lines", forgotten))
+        .await?;
+    Ok(())
 }
 
 #[command]
-#[owners_only]
 /// info resets the context
 async fn info(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let chat_target = crate::get_chat_target_from_message(msg)
-        .ok_or_else(|| StringError::from("Could not create chat target for message"))?;
-    let data_read = ctx.data.read().await;
-    let session_map = data_read
-        .get::<crate::SessionMapKey>()
-        .ok_or_else(|| StringError::from("Could not get read copy of session map"))?;
-    let session_map = Arc::clone(&session_map);
-    drop(data_read);
-    let session_map_read = session_map.read().await;
-    if let Some(session) = session_map_read.get(&chat_target) {
-        session.info(ctx, msg, args).await
-    } else {
-        Err(StringError::from("Chat target does not has a session").into())
-    }
+    let (_, handle) = get_message_session(ctx, msg).await?;
+    let session = handle.lock().await;
+    session.info(ctx, msg, args).await
 }
+
+#[command]
+/// usage shows how many tokens replies here spent today and this month, and who asked for them
+async fn usage(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let rules = get_config(ctx)
+        .await?
+        .read()
+        .await
+        .rules(msg.guild_id)
+        .cloned()
+        .unwrap_or_default();
+    let ledger = get_usage(ctx).await?;
+    let ledger = ledger.read().await;
+    let today = Date::today();
+    let account = ledger.account(msg.guild_id, msg.author.id);
+    let day = account.and_then(|account| account.day(today));
+    let month = account.and_then(|account| account.month(today));
+    let spent = |period: Option<&Period>, quota: Option<u64>| {
+        let tally = period.map(|period| period.total).unwrap_or_default();
+        match quota {
+            Some(quota) => format!("{} of {} allowed", tally, quota),
+            None => tally.to_string(),
+        }
+    };
+    let today_spent = spent(day, rules.daily_token_quota);
+    let month_spent = spent(month, rules.monthly_token_quota);
+    let channel_spent = month
+        .and_then(|month| month.channels.get(&msg.channel_id.0))
+        .copied()
+        .unwrap_or_default();
+    let top_users = month
+        .map(|month| month.top_users(5))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(user_id, tally)| format!("<@{}>: {} tokens", user_id, tally.tokens()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    drop(ledger);
+    msg.channel_id
+        .send_message(&ctx, |c_m| {
+            c_m.embed(|e| {
+                let e = e
+                    .title("Token usage")
+                    .field("today", today_spent, false)
+                    .field("this month", month_spent, false)
+                    .field("this channel, this month", channel_spent.to_string(), false);
+                if top_users.is_empty() {
+                    e
+                } else {
+                    e.field("top users this month", top_users, false)
+                }
+            })
+        })
+        .await?;
+    Ok(())
+}
+
 #[command]
-#[owners_only]
 /// disable will remove a session from the chat map, if it exists
 async fn disable(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
-    let chat_target = crate::get_chat_target_from_message(msg)
-        .ok_or_else(|| StringError::from("Could not create chat target for message"))?;
-    let data_read = ctx.data.read().await;
-    let session_map = data_read
-        .get::<crate::SessionMapKey>()
-        .ok_or_else(|| StringError::from("Could not get read copy of session map"))?;
-    let session_map = Arc::clone(&session_map);
-    drop(data_read);
-    let session_map_read = session_map.read().await;
-    if session_map_read.contains_key(&chat_target) {
-        drop(session_map_read);
-        let mut session_map_write = session_map.write().await;
-        session_map_write.remove(&chat_target);
-        msg.react(&ctx, '✅').await?;
+    let (chat_target, handle) = get_message_session(ctx, msg).await?;
+    // out of the map first, so lines arriving meanwhile can't start a new wait for a reply
+    let session_map = get_session_map(ctx).await?;
+    session_map.write().await.remove(&chat_target);
+    get_dispatcher(ctx).await?.cancel(&chat_target).await;
+    // anything else still working on the session has to finish before the saved copy goes,
+    // it won't save the session again now that it's out of the map
+    let _session = handle.lock().await;
+    get_storage(ctx).await?.remove_session(&chat_target)?;
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+/// Parses a role or user mention (or a bare role id) into who a grant is for
+fn parse_grantee(mention: &str) -> Result<Grantee, StringError> {
+    if let Some(role_id) = parse_role(mention) {
+        Ok(Grantee::Role(RoleId(role_id)))
+    } else if let Some(user_id) = parse_username(mention) {
+        Ok(Grantee::User(UserId(user_id)))
+    } else if let Ok(role_id) = mention.parse() {
+        Ok(Grantee::Role(RoleId(role_id)))
+    } else {
+        Err(format!("`{}` is not a role or user mention", mention).into())
+    }
+}
+
+/// Parses the remaining arguments as capabilities, where `all` expands to every capability
+fn parse_capabilities(args: &mut Args) -> Result<Vec<Capability>, StringError> {
+    let mut capabilities = Vec::new();
+    for arg in args.iter::<String>() {
+        let arg = arg?;
+        if arg.eq_ignore_ascii_case("all") {
+            capabilities.extend_from_slice(&Capability::ALL);
+        } else {
+            capabilities.push(arg.parse()?);
+        }
+    }
+    Ok(capabilities)
+}
+
+#[command]
+/// grant gives a role or user capabilities (enable, disable, reset, tune or all) in this guild
+async fn grant(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg
+        .guild_id
+        .ok_or_else(|| StringError::from("Permissions can only be granted in a guild"))?;
+    let grantee = parse_grantee(&args.single::<String>()?)?;
+    let capabilities = parse_capabilities(&mut args)?;
+    if capabilities.is_empty() {
+        return Err(StringError::from("Missing capabilities to grant").into());
+    }
+    let permissions = get_permissions(ctx).await?;
+    let mut permissions_write = permissions.write().await;
+    permissions_write.grant(guild_id, grantee, &capabilities);
+    get_storage(ctx).await?.save_permissions(&permissions_write)?;
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+#[command]
+/// revoke takes capabilities away from a role or user, or all of them if none are listed
+async fn revoke(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg
+        .guild_id
+        .ok_or_else(|| StringError::from("Permissions can only be revoked in a guild"))?;
+    let grantee = parse_grantee(&args.single::<String>()?)?;
+    let capabilities = parse_capabilities(&mut args)?;
+    let permissions = get_permissions(ctx).await?;
+    let mut permissions_write = permissions.write().await;
+    permissions_write.revoke(guild_id, grantee, &capabilities);
+    get_storage(ctx).await?.save_permissions(&permissions_write)?;
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+#[command]
+/// permissions lists every grant in this guild
+async fn permissions(ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+    let guild_id = msg
+        .guild_id
+        .ok_or_else(|| StringError::from("Permissions only exist in a guild"))?;
+    let grants = get_permissions(ctx).await?.read().await.describe(guild_id);
+    let description = if grants.is_empty() {
+        String::from("Only owners and administrators can manage sessions here")
+    } else {
+        grants
+            .iter()
+            .map(|(grantee, capabilities)| {
+                let capabilities: Vec<String> =
+                    capabilities.iter().map(ToString::to_string).collect();
+                format!("{}: {}", grantee, capabilities.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    msg.channel_id
+        .send_message(&ctx, |m| m.embed(|e| e.title("Permissions").description(description)))
+        .await?;
+    Ok(())
+}
+
+async fn get_config(ctx: &Context) -> Result<Arc<RwLock<crate::config::Config>>, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::ConfigKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get config"))
+}
+
+async fn get_usage(ctx: &Context) -> Result<Arc<RwLock<crate::usage::Ledger>>, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::UsageKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get token usage"))
+}
+
+/// Parses a guild id, where `here` means the guild the message was sent in
+fn single_guild_id(msg: &Message, args: &mut Args) -> Result<GuildId, StringError> {
+    let guild = args.single::<String>()?;
+    if guild.eq_ignore_ascii_case("here") {
+        msg.guild_id
+            .ok_or_else(|| StringError::from("`here` only works in a guild"))
+    } else {
+        guild
+            .parse()
+            .map(GuildId)
+            .map_err(|_| format!("`{}` is not a guild id", guild).into())
+    }
+}
+
+#[command]
+/// allow lets a guild (or `here`) enable the listed engines
+async fn allow(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = single_guild_id(msg, &mut args)?;
+    let engines = args.iter::<String>().collect::<Result<Vec<_>, _>>()?;
+    if engines.is_empty() {
+        return Err(StringError::from("Missing engines to allow").into());
+    }
+    let config = get_config(ctx).await?;
+    let mut config_write = config.write().await;
+    config_write.allow(guild_id, &engines);
+    get_storage(ctx).await?.save_config(&config_write)?;
+    let rules = config_write.rules(Some(guild_id)).cloned();
+    drop(config_write);
+    if let Some(rules) = rules {
+        get_dispatcher(ctx)
+            .await?
+            .apply_limits(Some(guild_id), &rules)
+            .await;
+    }
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+#[command]
+/// deny removes the listed engines from a guild (or `here`), or the whole guild if none are listed
+async fn deny(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = single_guild_id(msg, &mut args)?;
+    let engines = args.iter::<String>().collect::<Result<Vec<_>, _>>()?;
+    let config = get_config(ctx).await?;
+    let mut config_write = config.write().await;
+    config_write.deny(guild_id, &engines);
+    get_storage(ctx).await?.save_config(&config_write)?;
+    let rules = config_write.rules(Some(guild_id)).cloned();
+    drop(config_write);
+    if let Some(rules) = rules {
+        get_dispatcher(ctx)
+            .await?
+            .apply_limits(Some(guild_id), &rules)
+            .await;
+    }
+    msg.react(&ctx, '✅').await?;
+    Ok(())
+}
+
+async fn get_permissions(
+    ctx: &Context,
+) -> Result<Arc<RwLock<crate::permissions::Permissions>>, StringError> {
+    ctx.data
+        .read()
+        .await
+        .get::<crate::PermissionsKey>()
+        .map(Arc::clone)
+        .ok_or_else(|| StringError::from("Could not get permissions"))
+}
+
+/// Checks the command's [`Requirement`] against the author: owners pass everything, guild
+/// administrators pass everything in their guild, and everybody else needs a grant
+async fn check_permission(
+    ctx: &Context,
+    msg: &Message,
+    command_name: &str,
+) -> Result<(), StringError> {
+    let requirement = Requirement::for_command(command_name);
+    if requirement == Requirement::Nothing {
+        return Ok(());
+    }
+    let is_owner = ctx
+        .data
+        .read()
+        .await
+        .get::<crate::OwnersKey>()
+        .map_or(false, |owners| owners.contains(&msg.author.id));
+    if is_owner {
+        return Ok(());
+    } else if requirement == Requirement::Owner {
+        return Err(format!("Only owners can use `{}`", command_name).into());
+    }
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        // whoever the bot is DMing manages their own session, as far as the owners allow
+        None => {
+            let allowed = match requirement {
+                Requirement::Capability(_) => get_config(ctx)
+                    .await?
+                    .read()
+                    .await
+                    .allows_direct_messages_from(msg.author.id),
+                _ => false,
+            };
+            return if allowed {
+                Ok(())
+            } else {
+                Err(format!("You can't use `{}` in DMs", command_name).into())
+            };
+        }
+    };
+    let member = msg.member(&ctx).await?;
+    if member.permissions(&ctx).await?.administrator() {
+        return Ok(());
+    }
+    let capability = match requirement {
+        Requirement::Capability(capability) => capability,
+        _ => return Err(format!("Only administrators can use `{}`", command_name).into()),
+    };
+    let allowed = get_permissions(ctx).await?.read().await.allows(
+        guild_id,
+        msg.author.id,
+        &member.roles,
+        capability,
+    );
+    if allowed {
         Ok(())
     } else {
-        Err(StringError::from("Chat target does not has a session").into())
+        Err(format!(
+            "You need the `{}` capability to use `{}`",
+            capability, command_name
+        )
+        .into())
+    }
+}
+
+#[hook]
+pub async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
+    match check_permission(ctx, msg, command_name).await {
+        Ok(()) => true,
+        Err(why) => {
+            if let Err(send_msg_why) = msg
+                .channel_id
+                .send_message(&ctx.http, |m| m.embed(|e| e.description(&why)))
+                .await
+            {
+                eprintln!(
+                    "Failed to report permission failure: {:?}, {:?}",
+                    send_msg_why, why
+                )
+            }
+            false
+        }
     }
 }
 
@@ -189,3 +653,240 @@ pub async fn after(
         }
     }
 }
+
+/// What `max_tokens` can be set to, before the engine's context size and the guild's limit
+const MAX_TOKENS: RangeInclusive<usize> = 1..=4_096;
+
+/// Parses the next argument, rejecting it if it falls outside of `range`
+fn single_in_range<T>(
+    args: &mut Args,
+    name: &str,
+    range: RangeInclusive<T>,
+) -> Result<T, StringError>
+where
+    T: std::str::FromStr + PartialOrd + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    let value = args.single::<T>()?;
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "{} must be between {} and {}",
+            name,
+            range.start(),
+            range.end()
+        )
+        .into())
+    }
+}
+
+/// Applies `tuning` to the message's session and echoes the new state back with `info`
+async fn tune_session(ctx: &Context, msg: &Message, tuning: Tuning) -> CommandResult {
+    let (chat_target, handle) = get_message_session(ctx, msg).await?;
+    let rules = get_config(ctx)
+        .await?
+        .read()
+        .await
+        .rules(chat_target.guild_id)
+        .cloned();
+    let mut session = handle.lock().await;
+    session.tune(tuning, rules.as_ref())?;
+    get_dispatcher(ctx).await?.save_later(&chat_target);
+    session.info(ctx, msg, Args::new("", &[])).await
+}
+
+/// Parses the value for one of the `set` commands. Shared with the REPL so both enforce the same
+/// ranges
+pub fn parse_tuning(setting: &str, args: &mut Args) -> Result<Tuning, StringError> {
+    Ok(match setting {
+        "temperature" => Tuning::Temperature(single_in_range(args, setting, 0.0..=2.0)?),
+        "top_p" => Tuning::TopP(single_in_range(args, setting, 0.0..=1.0)?),
+        "presence_penalty" => Tuning::PresencePenalty(single_in_range(args, setting, -2.0..=2.0)?),
+        "frequency_penalty" => {
+            Tuning::FrequencyPenalty(single_in_range(args, setting, -2.0..=2.0)?)
+        }
+        "max_tokens" => Tuning::MaxTokens(single_in_range(args, setting, MAX_TOKENS)?),
+        "engine" => Tuning::Engine(args.single::<String>()?),
+        "context" => {
+            let context = args.rest().trim().trim_matches('`').trim_matches('"').trim();
+            if context.is_empty() {
+                return Err(StringError::from("Missing context"));
+            }
+            Tuning::Context(context.to_string())
+        }
+        "retries" => Tuning::MaxRetries(single_in_range(args, setting, 0..=10)?),
+        "mode" => Tuning::RequestMode(args.single::<String>()?.parse()?),
+        "streaming" => Tuning::Streaming(single_switch(args)?),
+        "summarizer" => Tuning::Summarizer(args.single::<String>()?.parse()?),
+        "trigger" => Tuning::Trigger(match &*args.single::<String>()?.to_lowercase() {
+            "prefix" => {
+                let prefix = args.single::<String>()?;
+                if prefix.starts_with(crate::COMMAND_IDENTIFIER) {
+                    return Err(format!(
+                        "The prefix can't start with `{}`",
+                        crate::COMMAND_IDENTIFIER
+                    )
+                    .into());
+                }
+                Trigger::Prefix(prefix)
+            }
+            "mention" => Trigger::Mention,
+            "reply" => Trigger::Reply,
+            "all" => Trigger::All,
+            "chime" => Trigger::ChimeIn(single_in_range(args, "rate", 0.0..=1.0)?),
+            other => {
+                return Err(format!(
+                    "Expected `prefix`, `mention`, `reply`, `all` or `chime`, got `{}`",
+                    other
+                )
+                .into())
+            }
+        }),
+        "listen" => Tuning::Listen(single_switch(args)?),
+        "first_delay" => Tuning::Delay(
+            Delay::FirstLine,
+            single_in_range(args, setting, 0..=60_000)?,
+        ),
+        "follow_up_delay" => {
+            Tuning::Delay(Delay::FollowUp, single_in_range(args, setting, 0..=60_000)?)
+        }
+        "max_delay" => Tuning::Delay(Delay::MaxWait, single_in_range(args, setting, 0..=600_000)?),
+        "reply_gap" => Tuning::Delay(
+            Delay::ReplyGap,
+            single_in_range(args, setting, 0..=600_000)?,
+        ),
+        _ => return Err(format!("Unknown setting `{}`", setting).into()),
+    })
+}
+
+/// Parses the next argument as `on` or `off`
+fn single_switch(args: &mut Args) -> Result<bool, StringError> {
+    match &*args.single::<String>()?.to_lowercase() {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        other => Err(format!("Expected `on` or `off`, got `{}`", other).into()),
+    }
+}
+
+#[command]
+/// temperature sets the sampling temperature, between 0 and 2
+async fn temperature(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("temperature", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// top_p sets the nucleus sampling probability mass, between 0 and 1
+async fn top_p(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("top_p", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// presence_penalty sets the penalty for tokens that already appeared, between -2 and 2
+async fn presence_penalty(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("presence_penalty", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// frequency_penalty sets the penalty for frequently repeated tokens, between -2 and 2
+async fn frequency_penalty(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("frequency_penalty", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// max_tokens sets how many tokens a single completion may generate
+async fn max_tokens(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let max_tokens = single_in_range(&mut args, "max_tokens", MAX_TOKENS)?;
+    tune_session(ctx, msg, Tuning::MaxTokens(max_tokens)).await
+}
+
+#[command]
+/// engine switches the engine (or the named model, for GPT2) used for completions
+async fn engine(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("engine", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// context replaces the context the conversation starts from
+async fn context(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("context", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// retries sets how many times a failed completion request is retried
+async fn retries(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("retries", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// mode switches between `text` completions and `chat` completions
+async fn mode(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("mode", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// streaming turns on posting replies right away and editing them as they're generated
+async fn streaming(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("streaming", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// summarizer picks how lines that no longer fit the prompt are summarized: `off`, `engine` or
+/// `local`
+async fn summarizer(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("summarizer", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// trigger sets which lines get a reply: `prefix <prefix>`, `mention`, `reply`, `all` or
+/// `chime <rate>` to answer that fraction of all lines
+async fn trigger(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("trigger", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// listen turns on recording lines that don't trigger a reply, as context for later replies
+async fn listen(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("listen", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// first_delay sets how long to wait after a line before replying, in milliseconds
+async fn first_delay(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("first_delay", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// follow_up_delay sets how much longer to wait for every line said while waiting, in
+/// milliseconds
+async fn follow_up_delay(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("follow_up_delay", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// max_delay sets the longest wait before replying however busy the channel is, in milliseconds
+async fn max_delay(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("max_delay", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
+
+#[command]
+/// reply_gap sets the shortest time between two replies, in milliseconds
+async fn reply_gap(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let tuning = parse_tuning("reply_gap", &mut args)?;
+    tune_session(ctx, msg, tuning).await
+}
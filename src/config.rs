@@ -0,0 +1,197 @@
+/// This file is the bot's TOML configuration, which decides which guilds (and DMs) may use which
+/// engines
+use serenity::model::id::{GuildId, UserId};
+
+/// ```toml
+/// [[guilds]]
+/// id = 394151608822398976
+/// engines = ["gpt2", "gpt3", "gpt-3.5-turbo"]
+/// default_engine = "gpt3"
+/// max_prompt_tokens = 1000
+/// max_tokens = 150
+/// daily_token_quota = 200000
+/// monthly_token_quota = 2000000
+///
+/// [direct_messages]
+/// engines = ["gpt3"]
+/// default_engine = "gpt3"
+/// users = [140232279438819329]
+/// ai_name = "Dorothy"
+/// context = "{name} is a friendly assistant chatting with someone in private"
+/// ```
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub guilds: Vec<GuildConfig>,
+    /// DMs are ignored unless this is set
+    pub direct_messages: Option<DirectMessageConfig>,
+}
+
+/// Which engines sessions may use and how big they may get, for a guild or for DMs
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct EngineRules {
+    /// Session kinds (`gpt2`, `gpt3`) that may be enabled, and the chat engines (`gpt-3.5-turbo`,
+    /// `gpt-4`) GPT3 sessions may switch to
+    #[serde(default)]
+    pub engines: Vec<String>,
+    /// Used for `!enable default ...`
+    pub default_engine: Option<String>,
+    /// Caps every session's prompt budget
+    pub max_prompt_tokens: Option<usize>,
+    /// Caps how many tokens a single completion may generate
+    pub max_tokens: Option<usize>,
+    /// Caps the prompt and completion tokens spent per day (UTC), across every session. For DMs
+    /// this is per user
+    pub daily_token_quota: Option<u64>,
+    /// Same as `daily_token_quota`, per calendar month
+    pub monthly_token_quota: Option<u64>,
+}
+
+impl EngineRules {
+    pub fn allows(&self, engine: &str) -> bool {
+        self.engines
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(engine))
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GuildConfig {
+    pub id: u64,
+    #[serde(flatten)]
+    pub rules: EngineRules,
+}
+
+impl GuildConfig {
+    fn new(guild_id: GuildId) -> GuildConfig {
+        GuildConfig {
+            id: guild_id.0,
+            rules: EngineRules::default(),
+        }
+    }
+}
+
+/// Private sessions, where whoever DMs the bot manages their own session
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct DirectMessageConfig {
+    #[serde(flatten)]
+    pub rules: EngineRules,
+    /// Who may have a session in their DMs. Nobody may if this is empty
+    #[serde(default)]
+    pub users: Vec<u64>,
+    /// Who the bot is in a DM session that was enabled without a persona, defaults to its name
+    pub ai_name: Option<String>,
+    /// What a DM session that was enabled without a persona starts from, `{name}` is replaced
+    /// with the AI's name
+    pub context: Option<String>,
+}
+
+impl Config {
+    pub fn guild(&self, guild_id: GuildId) -> Option<&GuildConfig> {
+        self.guilds.iter().find(|guild| guild.id == guild_id.0)
+    }
+
+    /// The rules for a guild, or for DMs if `guild_id` is `None`
+    pub fn rules(&self, guild_id: Option<GuildId>) -> Option<&EngineRules> {
+        match guild_id {
+            Some(guild_id) => self.guild(guild_id).map(|guild| &guild.rules),
+            None => self.direct_messages.as_ref().map(|dms| &dms.rules),
+        }
+    }
+
+    /// Whether `user_id` may have a session in their DMs with the bot
+    pub fn allows_direct_messages_from(&self, user_id: UserId) -> bool {
+        self.direct_messages
+            .as_ref()
+            .map_or(false, |dms| dms.users.contains(&user_id.0))
+    }
+
+    pub fn allow(&mut self, guild_id: GuildId, engines: &[String]) {
+        let guild = match self.guilds.iter().position(|guild| guild.id == guild_id.0) {
+            Some(index) => &mut self.guilds[index],
+            None => {
+                self.guilds.push(GuildConfig::new(guild_id));
+                self.guilds.last_mut().expect("A guild was just pushed")
+            }
+        };
+        for engine in engines {
+            let engine = engine.to_lowercase();
+            if !guild.rules.engines.contains(&engine) {
+                guild.rules.engines.push(engine);
+            }
+        }
+    }
+
+    /// Removes `engines` from the guild, or the whole guild if none are given
+    pub fn deny(&mut self, guild_id: GuildId, engines: &[String]) {
+        if engines.is_empty() {
+            self.guilds.retain(|guild| guild.id != guild_id.0);
+        } else if let Some(guild) = self.guilds.iter_mut().find(|guild| guild.id == guild_id.0) {
+            guild.rules.engines.retain(|engine| {
+                !engines
+                    .iter()
+                    .any(|denied| denied.eq_ignore_ascii_case(engine))
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_round_trips_through_toml() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[guilds]]
+            id = 394151608822398976
+            engines = ["gpt3"]
+            default_engine = "gpt3"
+            max_prompt_tokens = 1000
+            "#,
+        )
+        .expect("Config should parse");
+        let guild_id = GuildId(394151608822398976);
+        let allows = |config: &Config, guild_id, engine| {
+            config
+                .rules(Some(guild_id))
+                .map_or(false, |rules| rules.allows(engine))
+        };
+        assert!(allows(&config, guild_id, "GPT3"));
+        assert!(!allows(&config, guild_id, "gpt2"));
+        assert!(!allows(&config, GuildId(1), "gpt3"));
+
+        config.allow(GuildId(1), &[String::from("gpt2")]);
+        config.deny(guild_id, &[]);
+        let config: Config =
+            toml::from_str(&toml::to_string(&config).expect("Config should serialize"))
+                .expect("Config should parse");
+        assert!(allows(&config, GuildId(1), "gpt2"));
+        assert!(config.guild(guild_id).is_none());
+        assert!(config.rules(None).is_none());
+    }
+
+    #[test]
+    fn direct_messages_have_their_own_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [direct_messages]
+            engines = ["gpt3"]
+            max_tokens = 100
+            users = [20]
+            context = "{name} is chatting in private"
+            "#,
+        )
+        .expect("Config should parse");
+        let rules = config.rules(None).expect("DMs are configured");
+        assert!(rules.allows("gpt3"));
+        assert_eq!(rules.max_tokens, Some(100));
+        assert!(config.allows_direct_messages_from(UserId(20)));
+        assert!(!config.allows_direct_messages_from(UserId(30)));
+        assert!(!Config::default().allows_direct_messages_from(UserId(20)));
+        let no_users: Config = toml::from_str("[direct_messages]\nengines = [\"gpt3\"]\n")
+            .expect("Config should parse");
+        assert!(!no_users.allows_direct_messages_from(UserId(20)));
+    }
+}
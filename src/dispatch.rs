@@ -0,0 +1,1112 @@
+/// This file is the platform independent half of message handling: recording lines into sessions
+/// and waiting for the conversation to go quiet before asking for a reply
+use crate::{
+    channel::{Timing, Trigger},
+    config::{Config, EngineRules},
+    engines::{gpt2, gpt3, summarization, MessageSessionHandler},
+    get_session,
+    jobs::{Busy, CancellableTask, JobQueue},
+    platform::ChatPlatform,
+    storage, tokenizer,
+    transformers::conversation::LogItem,
+    usage::{self, Ledger, QuotaExceeded, Tally},
+    ChatTarget, Session, Snapshot, ThreadsafeSessionMap, COMMAND_IDENTIFIER,
+};
+use futures::FutureExt;
+use serenity::{
+    model::id::{ChannelId, GuildId, MessageId, UserId},
+    prelude::RwLock,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{sync::mpsc, time};
+
+/// How long recorded usage may wait before it's written out, so busy channels don't rewrite the
+/// ledger on every reply
+const USAGE_SAVE_DELAY: time::Duration = time::Duration::from_secs(10);
+
+/// How long a changed session may wait before it's written out, for the same reason
+const SESSION_SAVE_DELAY: time::Duration = time::Duration::from_secs(5);
+
+/// A chat line, stripped down to what the pipeline needs
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub chat_target: ChatTarget,
+    pub message_id: MessageId,
+    pub author_id: UserId,
+    pub author_name: String,
+    pub author_is_bot: bool,
+    pub mentions_bot: bool,
+    /// The message this one replies to, if any
+    pub reply_to: Option<MessageId>,
+    /// Content with mentions already made safe
+    pub content: String,
+}
+
+pub struct Dispatcher {
+    session_map: ThreadsafeSessionMap,
+    /// Used for prolonging the delay for tasks that need to generate responses when multiple
+    /// people are talking, instead of responding 1:1
+    chat_timeout_map: RwLock<HashMap<ChatTarget, ChatTargetTimeoutCommunicator>>,
+    tokenizer: Arc<tokenizer::Tokenizer>,
+    responder: Arc<Responder>,
+}
+
+/// Everything needed to generate a reply for a session, shared with the timeout tasks
+struct Responder {
+    session_map: ThreadsafeSessionMap,
+    gpt2_generators: Arc<gpt2::GeneratorCache>,
+    tokenizer: Arc<tokenizer::Tokenizer>,
+    local_summarizer: Arc<summarization::LocalSummarizer>,
+    storage: Arc<storage::Storage>,
+    jobs: Arc<JobQueue>,
+    /// When each session's last reply was done, to keep replies apart
+    last_replies: Mutex<HashMap<ChatTarget, time::Instant>>,
+    /// For the token quotas
+    config: Arc<RwLock<Config>>,
+    usage: Arc<RwLock<Ledger>>,
+    /// Whether a save of `usage` is already on its way
+    usage_save_pending: Arc<AtomicBool>,
+    /// Sessions with a save already on its way
+    session_saves_pending: Arc<Mutex<HashSet<ChatTarget>>>,
+}
+
+struct ChatTargetTimeoutCommunicator {
+    /// Sends the author of every line that prolongs the wait
+    new_message_sender: mpsc::UnboundedSender<UserId>,
+    finished: Arc<AtomicBool>,
+    task: CancellableTask,
+}
+
+impl Dispatcher {
+    pub fn new(
+        session_map: ThreadsafeSessionMap,
+        tokenizer: Arc<tokenizer::Tokenizer>,
+        storage: Arc<storage::Storage>,
+        jobs: JobQueue,
+        config: Arc<RwLock<Config>>,
+        usage: Arc<RwLock<Ledger>>,
+    ) -> Dispatcher {
+        let responder = Arc::new(Responder {
+            session_map: Arc::clone(&session_map),
+            gpt2_generators: Arc::new(gpt2::GeneratorCache::default()),
+            tokenizer: Arc::clone(&tokenizer),
+            local_summarizer: Arc::new(summarization::LocalSummarizer::default()),
+            storage,
+            jobs: Arc::new(jobs),
+            last_replies: Mutex::new(HashMap::new()),
+            config,
+            usage,
+            usage_save_pending: Arc::new(AtomicBool::new(false)),
+            session_saves_pending: Arc::new(Mutex::new(HashSet::new())),
+        });
+        Dispatcher {
+            session_map,
+            chat_timeout_map: RwLock::new(HashMap::new()),
+            tokenizer,
+            responder,
+        }
+    }
+
+    /// Saves a session that was changed outside of the dispatcher, see [`Responder::save_later`]
+    pub fn save_later(&self, chat_target: &ChatTarget) {
+        self.responder.save_later(chat_target);
+    }
+
+    pub async fn should_respond_to_target(&self, chat_target: &ChatTarget) -> bool {
+        self.session_map.read().await.contains_key(chat_target)
+    }
+
+    /// The chat target with a session in `channel_id`, for events that only come with a channel
+    pub async fn chat_target_in(&self, channel_id: ChannelId) -> Option<ChatTarget> {
+        self.session_map
+            .read()
+            .await
+            .keys()
+            .find(|chat_target| chat_target.channel_id == channel_id)
+            .cloned()
+    }
+
+    pub async fn handle_message(&self, platform: Arc<dyn ChatPlatform>, message: IncomingMessage) {
+        if message.author_is_bot || message.content.starts_with(COMMAND_IDENTIFIER) {
+            return;
+        }
+        let chat_target = message.chat_target.clone();
+
+        let handle = match get_session(&self.session_map, &chat_target).await {
+            Some(handle) => handle,
+            None => return,
+        };
+        let settings = handle.lock().await.channel_settings().clone();
+        // finding out who wrote a message can take a request, so only sessions that care ask
+        let replies_to_bot = match message.reply_to {
+            Some(message_id) if settings.trigger == Trigger::Reply => {
+                platform
+                    .sent_by_us(chat_target.channel_id, message_id)
+                    .await
+            }
+            _ => false,
+        };
+        let triggered =
+            settings.is_triggered_by(&message.content, message.mentions_bot, replies_to_bot);
+        if !triggered && !settings.listen {
+            return;
+        }
+        let text = match settings.line_text(&message.content) {
+            Some(text) => text.to_string(),
+            None => return,
+        };
+
+        // nicknames only exist in guilds
+        let author_nick = match chat_target.guild_id {
+            Some(guild_id) => platform.resolve_nick(guild_id, message.author_id).await,
+            None => None,
+        };
+        let mut session = handle.lock().await;
+        let log_item = LogItem {
+            author_name: Some(message.author_name.clone()),
+            sent_by_ai: false,
+            author_nick,
+            text,
+            message_id: Some(message.message_id),
+        };
+        match &mut *session {
+            Session::GPT2(session) => {
+                if let Err(why) = session.record(log_item, &self.tokenizer) {
+                    eprintln!("Failed to record line: {:?}, {:?}", message.content, why);
+                }
+            }
+            Session::GPT3(session) => {
+                if let Err(why) = session.record(log_item, &self.tokenizer) {
+                    eprintln!("Failed to record line: {:?}, {:?}", message.content, why);
+                } else {
+                    eprintln!("token count: {}", session.token_count);
+                }
+            }
+        }
+        self.responder.save_later(&chat_target);
+        drop(session);
+        // lines recorded while listening are only context
+        if !triggered {
+            return;
+        }
+
+        let timeout_map_read = self.chat_timeout_map.read().await;
+        if let Some(Some(sender)) = timeout_map_read.get(&chat_target).map(|sender| {
+            if sender.finished.load(Ordering::SeqCst) {
+                None
+            } else {
+                Some(sender)
+            }
+        }) {
+            if let Err(why) = sender.new_message_sender.send(message.author_id) {
+                eprintln!("Failed to send prolonging message: {:?}", &why);
+            }
+        } else {
+            drop(timeout_map_read);
+            let mut timeout_map_write = self.chat_timeout_map.write().await;
+            // first message, and we aren't waiting on a timeout
+            // bounded by discord on the network side
+            let (tx, rx) = mpsc::unbounded_channel();
+            let finished_flag = Arc::new(AtomicBool::default());
+            let task = CancellableTask::spawn(timeout_task(TimeoutTaskPayload {
+                chat_target: chat_target.clone(),
+                platform,
+                new_message_receiver: rx,
+                requested_by: message.author_id,
+                finished_flag: Arc::clone(&finished_flag),
+                timing: settings.timing.clone(),
+                responder: Arc::clone(&self.responder),
+            }));
+            timeout_map_write.insert(
+                chat_target.clone(),
+                ChatTargetTimeoutCommunicator {
+                    new_message_sender: tx,
+                    finished: finished_flag,
+                    task,
+                },
+            );
+        }
+    }
+
+    /// Brings the line for an edited message up to date. Lines edited to no longer start with the
+    /// session's prefix are dropped, as if they had been sent that way
+    pub async fn handle_edit(
+        &self,
+        chat_target: &ChatTarget,
+        message_id: MessageId,
+        content: &str,
+    ) {
+        let handle = match get_session(&self.session_map, chat_target).await {
+            Some(handle) => handle,
+            None => return,
+        };
+        let mut session = handle.lock().await;
+        let text = session.channel_settings().line_text(content);
+        if session.edit_line(message_id, text, &self.tokenizer) {
+            self.responder.save_later(&chat_target);
+        }
+    }
+
+    /// Drops the lines for deleted messages, replies included
+    pub async fn handle_delete(&self, chat_target: &ChatTarget, message_ids: &[MessageId]) {
+        let handle = match get_session(&self.session_map, chat_target).await {
+            Some(handle) => handle,
+            None => return,
+        };
+        let mut session = handle.lock().await;
+        if session.remove_lines(message_ids, &self.tokenizer) > 0 {
+            self.responder.save_later(&chat_target);
+        }
+    }
+
+    /// Takes the session's last reply out of its log and deletes the message it went out as.
+    /// `None` if there's no session or nothing to take back
+    pub async fn undo(
+        &self,
+        platform: &dyn ChatPlatform,
+        chat_target: &ChatTarget,
+    ) -> Option<LogItem> {
+        let handle = get_session(&self.session_map, chat_target).await?;
+        let mut session = handle.lock().await;
+        let reply = session.undo(&self.tokenizer)?;
+        self.responder.save_later(&chat_target);
+        drop(session);
+
+        if let Some(message_id) = reply.message_id {
+            if let Err(why) = platform
+                .delete_message(chat_target.channel_id, message_id)
+                .await
+            {
+                eprintln!("Failed to delete taken back reply: {}", &why);
+            }
+        }
+        Some(reply)
+    }
+
+    /// Takes the session's last reply back and queues a new one, from the same prompt. Returns
+    /// `Ok(false)` if there was nothing to take back
+    pub async fn retry(
+        &self,
+        platform: Arc<dyn ChatPlatform>,
+        chat_target: &ChatTarget,
+        requested_by: UserId,
+    ) -> Result<bool, Busy> {
+        // checked first so a full queue doesn't cost the reply
+        if !self.responder.jobs.has_room() {
+            return Err(Busy);
+        }
+        if self.undo(&*platform, chat_target).await.is_none() {
+            return Ok(false);
+        }
+        self.responder
+            .queue_reply(platform, chat_target.clone(), requested_by)?;
+        Ok(true)
+    }
+
+    /// Stops everything on its way to becoming a reply in the session: the wait for the channel
+    /// to go quiet, the queued reply and the one being generated. Used before the session is
+    /// disabled or reset, so nothing from before turns up afterwards
+    pub async fn cancel(&self, chat_target: &ChatTarget) {
+        let waiting = self.chat_timeout_map.write().await.remove(chat_target);
+        if let Some(waiting) = waiting {
+            // stopped before the jobs are, in case it's about to queue one
+            waiting.task.cancel().await;
+        }
+        self.responder.jobs.cancel(chat_target).await;
+        // a reset or disabled session has no earlier reply to keep its distance from
+        self.responder
+            .last_replies
+            .lock()
+            .unwrap()
+            .remove(chat_target);
+    }
+
+    /// Clamps the settings of every session in the guild (or in DMs) to `rules`, after the
+    /// config changed
+    pub async fn apply_limits(&self, guild_id: Option<GuildId>, rules: &EngineRules) {
+        let handles: Vec<_> = self
+            .session_map
+            .read()
+            .await
+            .iter()
+            .filter(|(chat_target, _)| chat_target.guild_id == guild_id)
+            .map(|(chat_target, handle)| (chat_target.clone(), Arc::clone(handle)))
+            .collect();
+        for (chat_target, handle) in handles {
+            let mut session = handle.lock().await;
+            session.apply_limits(rules);
+            self.responder.save_later(&chat_target);
+        }
+    }
+
+    /// Drops the last `count` lines from the session's log, returning how many there were
+    pub async fn forget(&self, chat_target: &ChatTarget, count: usize) -> usize {
+        let handle = match get_session(&self.session_map, chat_target).await {
+            Some(handle) => handle,
+            None => return 0,
+        };
+        let mut session = handle.lock().await;
+        let forgotten = session.forget(count, &self.tokenizer);
+        self.responder.save_later(&chat_target);
+        forgotten.len()
+    }
+}
+
+impl Responder {
+    /// Generates a reply once a worker is free. What it costs goes on `requested_by`'s tab
+    fn queue_reply(
+        self: &Arc<Self>,
+        platform: Arc<dyn ChatPlatform>,
+        chat_target: ChatTarget,
+        requested_by: UserId,
+    ) -> Result<(), Busy> {
+        let responder = Arc::clone(self);
+        let job_target = chat_target.clone();
+        self.jobs.submit(
+            chat_target,
+            async move { responder.reply(&platform, &job_target, requested_by).await }.boxed(),
+        )
+    }
+
+    /// Saves the session for `chat_target` after [`SESSION_SAVE_DELAY`], along with whatever else
+    /// changes in it by then. Sessions disabled in the meantime aren't saved, so they don't come
+    /// back after a restart
+    fn save_later(&self, chat_target: &ChatTarget) {
+        if !self
+            .session_saves_pending
+            .lock()
+            .unwrap()
+            .insert(chat_target.clone())
+        {
+            return;
+        }
+        let session_map = Arc::clone(&self.session_map);
+        let storage = Arc::clone(&self.storage);
+        let pending = Arc::clone(&self.session_saves_pending);
+        let chat_target = chat_target.clone();
+        tokio::spawn(async move {
+            time::delay_for(SESSION_SAVE_DELAY).await;
+            // cleared first, so anything changed while writing gets a save of its own
+            pending.lock().unwrap().remove(&chat_target);
+            let handle = match get_session(&session_map, &chat_target).await {
+                Some(handle) => handle,
+                None => return,
+            };
+            // disabling takes the session out of the map before waiting for its lock, so one
+            // that's still there once locked can't lose its saved copy until this is written
+            let session = handle.lock().await;
+            let is_current = session_map
+                .read()
+                .await
+                .get(&chat_target)
+                .map_or(false, |current| Arc::ptr_eq(current, &handle));
+            if !is_current {
+                return;
+            }
+            let saved = session.save();
+            let written =
+                tokio::task::spawn_blocking(move || storage.save_session(&chat_target, saved))
+                    .await
+                    .map_err(|why| {
+                        crate::error::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, why))
+                    })
+                    .and_then(|written| written);
+            if let Err(why) = written {
+                eprintln!("Failed to save session: {}", &why);
+            }
+            drop(session);
+        });
+    }
+
+    /// Generates and sends a reply for the session, if it still exists and its guild has tokens
+    /// left to spend
+    async fn reply(
+        &self,
+        platform: &Arc<dyn ChatPlatform>,
+        chat_target: &ChatTarget,
+        requested_by: UserId,
+    ) {
+        // 0. start typing
+        // 1. turn session into string, template out to prompt model
+        // 2. request completion
+        // 3. add ai generated line
+        // 4. send ai generated line as response
+        // ???
+        // profit
+        let channel_id = chat_target.channel_id;
+        if let Err(exceeded) = self.check_quota(chat_target, requested_by).await {
+            let mut ledger = self.usage.write().await;
+            let notify = ledger.should_notify(chat_target, requested_by, &exceeded);
+            drop(ledger);
+            if notify {
+                self.save_usage_later();
+                if let Err(why) = platform
+                    .send_message(channel_id, &format!("⛔ {}", exceeded))
+                    .await
+                {
+                    eprintln!("Failed to report an exceeded quota: {}", &why);
+                }
+            }
+            return;
+        }
+        if let Err(why) = platform.broadcast_typing(channel_id).await {
+            eprintln!("Failed to broadcast typing: {:?}", &why);
+        }
+
+        let handle = if let Some(handle) = get_session(&self.session_map, chat_target).await {
+            handle
+        } else {
+            eprintln!("Failed to find session in map after timeout");
+            return;
+        };
+        // the session is only locked to copy it and to take the reply back in, so new lines and
+        // commands don't wait on the completion
+        let mut snapshot = handle.lock().await.snapshot();
+        let reply = match &mut snapshot {
+            Snapshot::GPT2(session) => {
+                let gpt2_payload = gpt2::Payload {
+                    channel_id,
+                    generators: Arc::clone(&self.gpt2_generators),
+                    tokenizer: Arc::clone(&self.tokenizer),
+                };
+                session.perform_work(platform, gpt2_payload).await
+            }
+            Snapshot::GPT3(snapshot) => {
+                let gpt3_payload = gpt3::Payload {
+                    channel_id,
+                    tokenizer: Arc::clone(&self.tokenizer),
+                    local_summarizer: Arc::clone(&self.local_summarizer),
+                };
+                snapshot.handler.perform_work(platform, gpt3_payload).await
+            }
+        };
+        let mut session = handle.lock().await;
+        session.commit(snapshot, reply, &self.tokenizer);
+        let spent = session.take_usage();
+        self.save_later(chat_target);
+        drop(session);
+        self.record_usage(chat_target, requested_by, &spent).await;
+        self.last_replies
+            .lock()
+            .unwrap()
+            .insert(chat_target.clone(), time::Instant::now());
+    }
+
+    async fn check_quota(
+        &self,
+        chat_target: &ChatTarget,
+        requested_by: UserId,
+    ) -> Result<(), QuotaExceeded> {
+        let rules = match self.config.read().await.rules(chat_target.guild_id) {
+            Some(rules) => rules.clone(),
+            None => return Ok(()),
+        };
+        self.usage.read().await.check_quota(
+            chat_target.guild_id,
+            requested_by,
+            &rules,
+            usage::Date::today(),
+        )
+    }
+
+    async fn record_usage(&self, chat_target: &ChatTarget, requested_by: UserId, spent: &Tally) {
+        if spent.requests == 0 {
+            return;
+        }
+        self.usage
+            .write()
+            .await
+            .record(chat_target, requested_by, spent, usage::Date::today());
+        self.save_usage_later();
+    }
+
+    /// Saves the ledger after [`USAGE_SAVE_DELAY`], along with whatever else is recorded by then
+    fn save_usage_later(&self) {
+        if self.usage_save_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let usage = Arc::clone(&self.usage);
+        let storage = Arc::clone(&self.storage);
+        let pending = Arc::clone(&self.usage_save_pending);
+        tokio::spawn(async move {
+            time::delay_for(USAGE_SAVE_DELAY).await;
+            // cleared first, so anything recorded while writing gets a save of its own
+            pending.store(false, Ordering::SeqCst);
+            let ledger = usage.read().await;
+            if let Err(why) = storage.save_usage(&ledger) {
+                eprintln!("Failed to save usage: {}", &why);
+            }
+        });
+    }
+
+    /// The earliest the session's next reply may go out, going by when its last one was done
+    fn next_reply_at(
+        &self,
+        chat_target: &ChatTarget,
+        gap: time::Duration,
+    ) -> Option<time::Instant> {
+        self.last_replies
+            .lock()
+            .unwrap()
+            .get(chat_target)
+            .map(|last_reply| *last_reply + gap)
+    }
+}
+
+struct TimeoutTaskPayload {
+    new_message_receiver: mpsc::UnboundedReceiver<UserId>,
+    /// Whoever said the last line, who the reply is for
+    requested_by: UserId,
+    finished_flag: Arc<AtomicBool>,
+    /// The session's timing when the first line came in
+    timing: Timing,
+    platform: Arc<dyn ChatPlatform>,
+    chat_target: ChatTarget,
+    responder: Arc<Responder>,
+}
+
+async fn timeout_task(mut payload: TimeoutTaskPayload) {
+    let timing = &payload.timing;
+    let started = time::Instant::now();
+    let earliest = payload
+        .responder
+        .next_reply_at(&payload.chat_target, timing.reply_gap())
+        .unwrap_or(started);
+    // every line pushes the reply back, up to the max wait, but never closer to the last reply
+    // than the gap
+    let reply_at = |wait: time::Duration| {
+        (time::Instant::now() + wait)
+            .min(started + timing.max_wait())
+            .max(earliest)
+    };
+    let mut delay = time::delay_until(reply_at(timing.first_line()));
+    loop {
+        tokio::select! {
+            _ = &mut delay => break,
+            author_id = payload.new_message_receiver.recv() => {
+                if let Some(author_id) = author_id {
+                    payload.requested_by = author_id;
+                }
+                delay.reset(reply_at(timing.follow_up()));
+            }
+        }
+    }
+    payload.finished_flag.store(true, Ordering::SeqCst);
+    let queued = payload.responder.queue_reply(
+        Arc::clone(&payload.platform),
+        payload.chat_target.clone(),
+        payload.requested_by,
+    );
+    if let Err(busy) = queued {
+        if let Err(why) = payload
+            .platform
+            .send_message(payload.chat_target.channel_id, &format!("⏳ {}", busy))
+            .await
+        {
+            eprintln!("Failed to report a full queue: {}", &why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::ChannelSettings,
+        config::GuildConfig,
+        engines::openai::{Choice, Completion, CompletionParameters, CompletionProvider, FinishReason},
+        jobs::settle,
+        platform::{Event, InMemory},
+        transformers::{conversation, TransformerKind},
+        SharedSession,
+    };
+    use std::sync::{atomic::AtomicU64, Mutex};
+
+    /// Always replies with the same line, and remembers every prompt it was asked to complete
+    #[derive(Default)]
+    struct Parrot {
+        prompts: Mutex<Vec<String>>,
+        /// Completions don't finish while this is set, like a slow request
+        held: AtomicBool,
+    }
+
+    #[serenity::async_trait]
+    impl CompletionProvider for Parrot {
+        async fn create_completion(
+            &self,
+            params: &CompletionParameters,
+        ) -> crate::error::Result<Completion> {
+            self.prompts
+                .lock()
+                .unwrap()
+                .push(params.prompt.clone().unwrap_or_default());
+            while self.held.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+            Ok(Completion {
+                id: String::from("cmpl-parrot"),
+                object: None,
+                created: 0,
+                model: String::from("parrot"),
+                choices: vec![Choice {
+                    text: String::from(" hi!"),
+                    index: 0,
+                    logprobs: None,
+                    finish_reason: Some(FinishReason::Stop),
+                }],
+            })
+        }
+    }
+
+    struct Fixture {
+        dispatcher: Dispatcher,
+        session_map: ThreadsafeSessionMap,
+        platform: Arc<InMemory>,
+        provider: Arc<Parrot>,
+        config: Arc<RwLock<Config>>,
+        usage: Arc<RwLock<Ledger>>,
+        _data_dir: DataDir,
+    }
+
+    /// Where a fixture's storage saves to, removed again once the test is done
+    struct DataDir(std::path::PathBuf);
+
+    impl Drop for DataDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn chat_target() -> ChatTarget {
+        ChatTarget {
+            guild_id: Some(GuildId(1)),
+            channel_id: ChannelId(2),
+        }
+    }
+
+    static LAST_MESSAGE_ID: AtomicU64 = AtomicU64::new(1_000);
+
+    fn line(author_id: u64, author_name: &str, content: &str) -> IncomingMessage {
+        IncomingMessage {
+            chat_target: chat_target(),
+            message_id: MessageId(LAST_MESSAGE_ID.fetch_add(1, Ordering::SeqCst)),
+            author_id: UserId(author_id),
+            author_name: author_name.to_string(),
+            author_is_bot: false,
+            mentions_bot: false,
+            reply_to: None,
+            content: content.to_string(),
+        }
+    }
+
+    fn new_session(provider: &Arc<Parrot>) -> Session {
+        Session::GPT3(gpt3::GPT3MessageHandler::new(
+            TransformerKind::Conversation(conversation::Transformer {
+                ai_name: String::from("Ai"),
+                context: None,
+                summary: None,
+            }),
+            Arc::clone(provider) as Arc<dyn CompletionProvider>,
+        ))
+    }
+
+    async fn fixture(name: &str) -> Fixture {
+        let data_dir =
+            std::env::temp_dir().join(format!("dorothy-dispatch-{}-{}", name, std::process::id()));
+        let storage = Arc::new(storage::Storage::new(data_dir.clone()).unwrap());
+        let provider = Arc::new(Parrot::default());
+        let session_map: ThreadsafeSessionMap = Arc::new(RwLock::new(HashMap::new()));
+        let config = Arc::new(RwLock::new(Config::default()));
+        let usage = Arc::new(RwLock::new(Ledger::default()));
+        session_map
+            .write()
+            .await
+            .insert(chat_target(), new_session(&provider).into_shared());
+        Fixture {
+            dispatcher: Dispatcher::new(
+                Arc::clone(&session_map),
+                Arc::new(tokenizer::test_tokenizer()),
+                storage,
+                JobQueue::new(2, 8),
+                Arc::clone(&config),
+                Arc::clone(&usage),
+            ),
+            config,
+            usage,
+            session_map,
+            platform: Arc::new(InMemory::default().with_nick(GuildId(1), UserId(10), "Fooey")),
+            provider,
+            _data_dir: DataDir(data_dir),
+        }
+    }
+
+    impl Fixture {
+        async fn session(&self) -> SharedSession {
+            get_session(&self.session_map, &chat_target())
+                .await
+                .expect("The session was enabled")
+        }
+
+        async fn say(&self, message: IncomingMessage) {
+            let platform = Arc::clone(&self.platform) as Arc<dyn ChatPlatform>;
+            self.dispatcher.handle_message(platform, message).await;
+            settle().await;
+        }
+    }
+
+    async fn advance(millis: u64) {
+        time::advance(time::Duration::from_millis(millis)).await;
+        settle().await;
+    }
+
+    #[tokio::test]
+    async fn replies_once_the_channel_goes_quiet() {
+        time::pause();
+        let fixture = fixture("quiet").await;
+        fixture.say(line(10, "foo", ">hello")).await;
+
+        advance(2_400).await;
+        assert!(fixture.platform.events().is_empty());
+
+        advance(200).await;
+        assert_eq!(
+            fixture.platform.events(),
+            vec![
+                Event::Typing {
+                    channel_id: ChannelId(2)
+                },
+                Event::Message {
+                    channel_id: ChannelId(2),
+                    text: String::from("hi!")
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn follow_up_lines_push_the_reply_back() {
+        time::pause();
+        let fixture = fixture("follow-up").await;
+        fixture.say(line(10, "foo", ">first")).await;
+        advance(2_000).await;
+        fixture.say(line(11, "bar", ">second")).await;
+
+        // 2.5s after the first line, but only 0.5s after the second
+        advance(1_400).await;
+        assert!(fixture.platform.messages().is_empty());
+
+        advance(200).await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+        let prompts = fixture.provider.prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 1, "Both lines should get a single reply");
+        assert!(prompts[0].contains("User (Fooey): first\nUser (bar): second\n"));
+    }
+
+    #[tokio::test]
+    async fn busy_channels_get_an_answer_and_replies_keep_their_gap() {
+        time::pause();
+        let fixture = fixture("timing").await;
+        if let Session::GPT3(session) = &mut *fixture.session().await.lock().await {
+            session.channel_settings.timing.max_wait_ms = 4_000;
+            session.channel_settings.timing.reply_gap_ms = 10_000;
+        }
+        fixture.say(line(10, "foo", ">one")).await;
+        for _ in 0..3 {
+            advance(1_000).await;
+            fixture.say(line(11, "bar", ">more")).await;
+        }
+        advance(900).await;
+        assert!(fixture.platform.messages().is_empty());
+        advance(200).await;
+        assert_eq!(fixture.platform.messages().len(), 1);
+
+        fixture.say(line(10, "foo", ">again")).await;
+        advance(9_800).await;
+        assert_eq!(fixture.platform.messages().len(), 1);
+        advance(300).await;
+        assert_eq!(fixture.platform.messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn session_removed_while_waiting_gets_no_reply() {
+        time::pause();
+        let fixture = fixture("removed").await;
+        fixture.say(line(10, "foo", ">hello")).await;
+        advance(1_000).await;
+        fixture.session_map.write().await.remove(&chat_target());
+
+        advance(5_000).await;
+        assert!(fixture.platform.messages().is_empty());
+        assert!(fixture.provider.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_sessions_start_waiting_afresh() {
+        time::pause();
+        let fixture = fixture("cancelled").await;
+        fixture.say(line(10, "foo", ">hello")).await;
+        advance(1_000).await;
+        fixture.dispatcher.cancel(&chat_target()).await;
+
+        advance(5_000).await;
+        assert!(fixture.platform.events().is_empty());
+        assert!(fixture.provider.prompts.lock().unwrap().is_empty());
+
+        fixture.say(line(10, "foo", ">still there?")).await;
+        advance(2_600).await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+    }
+
+    #[tokio::test]
+    async fn replies_are_metered_and_stop_at_the_quota() {
+        time::pause();
+        let fixture = fixture("quota").await;
+        fixture.config.write().await.guilds.push(GuildConfig {
+            id: 1,
+            rules: EngineRules {
+                daily_token_quota: Some(1),
+                ..EngineRules::default()
+            },
+        });
+        fixture.say(line(10, "foo", ">hello")).await;
+        advance(2_600).await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+        {
+            let ledger = fixture.usage.read().await;
+            let today = ledger
+                .account(Some(GuildId(1)), UserId(10))
+                .and_then(|account| account.day(usage::Date::today()))
+                .expect("The reply should be on the guild's tab");
+            assert_eq!(today.total.requests, 1);
+            assert!(today.users[&10].tokens() > 0);
+        }
+
+        fixture.say(line(11, "bar", ">again")).await;
+        advance(2_600).await;
+        assert_eq!(fixture.provider.prompts.lock().unwrap().len(), 1);
+        assert!(fixture.platform.messages()[1].starts_with("⛔ The daily quota of 1 tokens"));
+
+        fixture.say(line(10, "foo", ">and again")).await;
+        advance(2_600).await;
+        assert_eq!(
+            fixture.platform.messages().len(),
+            2,
+            "The channel was already told"
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_lines_not_meant_for_the_bot() {
+        time::pause();
+        let fixture = fixture("ignored").await;
+        fixture
+            .say(IncomingMessage {
+                author_is_bot: true,
+                ..line(12, "bot", ">beep")
+            })
+            .await;
+        fixture.say(line(10, "foo", "!info")).await;
+        fixture.say(line(10, "foo", "no prefix")).await;
+
+        advance(5_000).await;
+        assert!(fixture.platform.events().is_empty());
+        match &*fixture.session().await.lock().await {
+            Session::GPT3(session) => assert!(session.message_log.is_empty()),
+            Session::GPT2(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_busy_session_does_not_hold_up_other_channels() {
+        let fixture = fixture("busy").await;
+        let other_target = ChatTarget {
+            guild_id: Some(GuildId(1)),
+            channel_id: ChannelId(3),
+        };
+        fixture.session_map.write().await.insert(
+            other_target.clone(),
+            new_session(&fixture.provider).into_shared(),
+        );
+        // as if a reply was being generated
+        let busy = fixture.session().await;
+        let _busy = busy.lock().await;
+
+        let message = IncomingMessage {
+            chat_target: other_target.clone(),
+            ..line(10, "foo", ">hello")
+        };
+        time::timeout(time::Duration::from_secs(1), fixture.say(message))
+            .await
+            .expect("Recording shouldn't wait on another channel's session");
+        let other = get_session(&fixture.session_map, &other_target)
+            .await
+            .expect("The session was enabled");
+        match &*other.lock().await {
+            Session::GPT3(session) => assert_eq!(session.message_log.len(), 1),
+            Session::GPT2(_) => unreachable!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn lines_said_during_a_reply_are_kept() {
+        time::pause();
+        let fixture = fixture("during").await;
+        fixture.provider.held.store(true, Ordering::SeqCst);
+        fixture.say(line(10, "foo", ">hello")).await;
+        advance(2_600).await;
+        assert_eq!(fixture.provider.prompts.lock().unwrap().len(), 1);
+
+        fixture.say(line(11, "bar", ">meanwhile")).await;
+        fixture.provider.held.store(false, Ordering::SeqCst);
+        settle().await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+        match &*fixture.session().await.lock().await {
+            Session::GPT3(session) => assert_eq!(
+                session
+                    .message_log
+                    .iter()
+                    .map(|log_item| &*log_item.text)
+                    .collect::<Vec<_>>(),
+                vec!["hello", "meanwhile", "hi!"]
+            ),
+            Session::GPT2(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn listening_records_every_line_but_only_answers_the_trigger() {
+        time::pause();
+        let fixture = fixture("listening").await;
+        if let Session::GPT3(session) = &mut *fixture.session().await.lock().await {
+            session.channel_settings = ChannelSettings {
+                trigger: Trigger::Mention,
+                listen: true,
+                ..ChannelSettings::default()
+            };
+        }
+        fixture.say(line(11, "bar", "anyone around?")).await;
+        advance(5_000).await;
+        assert!(fixture.platform.events().is_empty());
+
+        fixture
+            .say(IncomingMessage {
+                mentions_bot: true,
+                ..line(10, "foo", "@Ai are you?")
+            })
+            .await;
+        advance(2_600).await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+        let prompts = fixture.provider.prompts.lock().unwrap().clone();
+        assert!(prompts[0].contains("User (bar): anyone around?\nUser (Fooey): @Ai are you?\n"));
+    }
+
+    #[tokio::test]
+    async fn replies_to_the_bot_trigger_even_once_out_of_the_log() {
+        time::pause();
+        let fixture = fixture("replies").await;
+        if let Session::GPT3(session) = &mut *fixture.session().await.lock().await {
+            session.channel_settings = ChannelSettings {
+                trigger: Trigger::Reply,
+                ..ChannelSettings::default()
+            };
+        }
+        // said before the session's log began
+        let earlier = fixture
+            .platform
+            .send_message(ChannelId(2), "hello from earlier")
+            .await
+            .unwrap();
+        fixture
+            .say(IncomingMessage {
+                reply_to: Some(MessageId(999)),
+                ..line(11, "bar", "not to you")
+            })
+            .await;
+        advance(5_000).await;
+        assert_eq!(fixture.platform.messages().len(), 1);
+
+        fixture
+            .say(IncomingMessage {
+                reply_to: Some(earlier),
+                ..line(10, "foo", "hi again")
+            })
+            .await;
+        advance(2_600).await;
+        assert_eq!(fixture.platform.messages()[1..], [String::from("hi!")]);
+    }
+
+    #[tokio::test]
+    async fn retry_replaces_the_last_reply() {
+        time::pause();
+        let fixture = fixture("retry").await;
+        fixture.say(line(10, "foo", ">hello")).await;
+        advance(2_600).await;
+        assert_eq!(fixture.platform.messages(), vec![String::from("hi!")]);
+
+        let platform = Arc::clone(&fixture.platform) as Arc<dyn ChatPlatform>;
+        assert!(fixture
+            .dispatcher
+            .retry(platform, &chat_target(), UserId(10))
+            .await
+            .expect("The queue has room"));
+        settle().await;
+        assert_eq!(
+            fixture.platform.events()[2..],
+            [
+                Event::Delete {
+                    message_id: MessageId(1)
+                },
+                Event::Typing {
+                    channel_id: ChannelId(2)
+                },
+                Event::Message {
+                    channel_id: ChannelId(2),
+                    text: String::from("hi!")
+                },
+            ]
+        );
+        let prompts = fixture.provider.prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 2);
+        assert_eq!(
+            prompts[0], prompts[1],
+            "The retry should see the same conversation"
+        );
+        match &*fixture.session().await.lock().await {
+            Session::GPT3(session) => {
+                assert_eq!(session.message_log.len(), 2);
+                assert_eq!(session.message_log[1].message_id, Some(MessageId(2)));
+            }
+            Session::GPT2(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn edits_and_deletions_update_the_log() {
+        time::pause();
+        let fixture = fixture("edits").await;
+        let first = line(10, "foo", ">helo");
+        let second = line(11, "bar", ">second");
+        fixture.say(first.clone()).await;
+        fixture.say(second.clone()).await;
+        fixture
+            .dispatcher
+            .handle_edit(&chat_target(), first.message_id, ">hello")
+            .await;
+        fixture
+            .dispatcher
+            .handle_delete(&chat_target(), &[second.message_id])
+            .await;
+
+        advance(5_000).await;
+        let prompts = fixture.provider.prompts.lock().unwrap().clone();
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].contains("User (Fooey): hello\n"));
+        assert!(!prompts[0].contains("second"));
+    }
+}
@@ -1,18 +1,45 @@
 /// This file is the preferred interface for local GPT2
+use super::Tuning;
+use crate::channel::ChannelSettings;
+use crate::commands::StringError;
+use crate::platform::ChatPlatform;
+use crate::tokenizer::Tokenizer;
 use crate::transformers::{self, conversation::LogItem, TransformerKind};
 use crate::Session;
+use rust_bert::{
+    pipelines::generation_utils::{GPT2Generator, GenerateConfig, LanguageGenerator},
+    resources::{LocalResource, Resource},
+};
 use serenity::{
     framework::standard::{Args, CommandError, CommandResult},
-    model::{channel::Message, id::ChannelId},
+    model::{
+        channel::Message,
+        id::{ChannelId, MessageId},
+    },
     prelude::Context,
 };
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-#[derive(Debug)]
+/// Used when `!enable gpt2 default ...` is given and `GPT2_MODEL_DIR` isn't set
+const DEFAULT_MODEL_DIR: &str = "models/gpt2";
+/// Where models picked by name are looked up when `GPT2_MODELS_DIR` isn't set
+const DEFAULT_MODELS_DIR: &str = "models";
+/// Held back from `max_length` for the reply, the prompt gets the rest
+const REPLY_TOKENS: usize = 100;
+/// What GPT2 ends (and pads) a finished sequence with
+const END_OF_TEXT: &str = "<|endoftext|>";
+
+#[derive(Debug, Clone)]
 pub struct GPT2MessageHandler {
     pub transformer: TransformerKind,
     pub message_log: Vec<LogItem>,
     pub configuration: Configuration,
+    pub channel_settings: ChannelSettings,
 }
 
 impl GPT2MessageHandler {
@@ -22,12 +49,358 @@ impl GPT2MessageHandler {
             transformer,
             message_log: Vec::new(),
             configuration,
+            channel_settings: ChannelSettings::default(),
+        }
+    }
+
+    pub fn set_model_dir(&mut self, model_dir: PathBuf) {
+        self.configuration.model_dir = model_dir;
+    }
+
+    /// Copies out everything needed to rebuild this handler after a restart
+    pub fn save(&self) -> SavedSession {
+        SavedSession {
+            transformer: self.transformer.clone(),
+            message_log: self.message_log.clone(),
+            configuration: self.configuration.clone(),
+            channel_settings: self.channel_settings.clone(),
+        }
+    }
+
+    pub fn restore(saved: SavedSession) -> GPT2MessageHandler {
+        GPT2MessageHandler {
+            transformer: saved.transformer,
+            message_log: saved.message_log,
+            configuration: saved.configuration,
+            channel_settings: saved.channel_settings,
+        }
+    }
+
+    /// Forgets the conversation, keeping the settings
+    pub fn clear(&mut self) {
+        self.message_log.clear();
+    }
+
+    pub fn record(&mut self, log_item: LogItem, tokenizer: &Tokenizer) -> crate::error::Result<()> {
+        self.message_log.push(log_item);
+        self.ensure_is_safe(tokenizer)
+    }
+
+    /// Drops the oldest log lines that wouldn't make it into a prompt anymore, so the log
+    /// doesn't grow forever
+    pub fn ensure_is_safe(&mut self, tokenizer: &Tokenizer) -> crate::error::Result<()> {
+        let first_line = self.first_line_within(tokenizer, self.prompt_tokens())?;
+        self.message_log.drain(..first_line);
+        Ok(())
+    }
+
+    /// Takes the most recent reply out of the log
+    pub fn undo(&mut self) -> Option<LogItem> {
+        super::remove_last_reply(&mut self.message_log)
+    }
+
+    /// Takes the last `count` lines out of the log
+    pub fn forget(&mut self, count: usize) -> Vec<LogItem> {
+        super::remove_last_lines(&mut self.message_log, count)
+    }
+
+    /// Updates or drops the line someone sent as `message_id`, returning whether there was one
+    pub fn edit_line(&mut self, message_id: MessageId, text: Option<&str>) -> bool {
+        super::edit_line(&mut self.message_log, message_id, text)
+    }
+
+    /// Drops the lines for `message_ids`, returning how many there were
+    pub fn remove_lines(&mut self, message_ids: &[MessageId]) -> usize {
+        super::remove_lines(&mut self.message_log, message_ids)
+    }
+
+    pub fn make_prompt(&self) -> Result<String, std::fmt::Error> {
+        self.make_prompt_from(0)
+    }
+
+    /// The prompt with the log starting at `first_line`
+    fn make_prompt_from(&self, first_line: usize) -> Result<String, std::fmt::Error> {
+        let mut buf = String::new();
+        self.transformer.prepare(&mut buf)?;
+        for log_item in &self.message_log[first_line..] {
+            self.transformer.transform(&mut buf, log_item)?;
+        }
+        self.transformer.append_prompt(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Tokens held back from `max_length` for the reply
+    fn reply_tokens(&self) -> usize {
+        REPLY_TOKENS.min(self.configuration.max_length as usize / 2)
+    }
+
+    /// Tokens left for the prompt, since `max_length` counts it too
+    fn prompt_tokens(&self) -> usize {
+        self.configuration.max_length as usize - self.reply_tokens()
+    }
+
+    /// The oldest log line a prompt of at most `max_tokens` can start from, adding up what each
+    /// line costs from the newest one back
+    fn first_line_within(
+        &self,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> crate::error::Result<usize> {
+        let mut first_line = self.message_log.len();
+        let mut tokens = tokenizer.count(&self.make_prompt_from(first_line)?);
+        let mut line = String::new();
+        while first_line > 0 {
+            line.clear();
+            self.transformer
+                .transform(&mut line, &self.message_log[first_line - 1])?;
+            let line_tokens = tokenizer.count(&line);
+            if tokens + line_tokens > max_tokens {
+                break;
+            }
+            tokens += line_tokens;
+            first_line -= 1;
+        }
+        Ok(first_line)
+    }
+
+    /// Leaves out the oldest log lines until the prompt is at most `max_tokens` long. Returns
+    /// the prompt and how many tokens it is
+    fn make_trimmed_prompt(
+        &self,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> crate::error::Result<(String, usize)> {
+        let mut first_line = self.first_line_within(tokenizer, max_tokens)?;
+        loop {
+            let prompt = self.make_prompt_from(first_line)?;
+            let prompt_tokens = tokenizer.count(&prompt);
+            if prompt_tokens <= max_tokens {
+                return Ok((prompt, prompt_tokens));
+            }
+            // merges across lines can make the whole prompt a token or two longer than its parts
+            if first_line == self.message_log.len() {
+                return Err(crate::error::Error::Generation(String::from(
+                    "The context is too long for GPT2",
+                )));
+            }
+            first_line += 1;
+        }
+    }
+
+    /// Runs generation on the blocking pool, since a forward pass can take seconds on CPU
+    pub async fn get_response(
+        &self,
+        generators: &GeneratorCache,
+        tokenizer: &Tokenizer,
+    ) -> crate::error::Result<String> {
+        let generator = generators.get(&self.configuration).await?;
+        // going past the model's context panics
+        let reply_tokens = self.reply_tokens();
+        let (prompt, prompt_tokens) = self.make_trimmed_prompt(tokenizer, self.prompt_tokens())?;
+        let output = tokio::task::spawn_blocking(move || {
+            // the model isn't left half updated by a panicking generation
+            let generator = generator
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            generator.generate_indices(
+                Some(&[&*prompt]),
+                None,
+                None,
+                (prompt_tokens + reply_tokens) as i64,
+                None,
+            )
+        })
+        .await
+        .map_err(|why| crate::error::Error::Generation(why.to_string()))?;
+        let generated = output
+            .into_iter()
+            .next()
+            .ok_or_else(|| crate::error::Error::Generation(String::from("No output")))?;
+        // the generator echoes the prompt back before the continuation, and both use GPT2's
+        // vocabulary so the prompt is as many tokens for it as for us
+        let continuation: Vec<u32> = generated
+            .into_iter()
+            .skip(prompt_tokens)
+            .map(|id| id as u32)
+            .collect();
+        let continuation = tokenizer.decode(&continuation);
+        let mut stop_tokens = self.transformer.get_stop_params().unwrap_or_default();
+        stop_tokens.push(String::from(END_OF_TEXT));
+        Ok(truncate_at_stop_tokens(&continuation, &stop_tokens).to_string())
+    }
+
+    /// Everything `perform_work` does short of talking to Discord. Returns the line to send, if
+    /// there is one
+    pub async fn generate_reply(
+        &mut self,
+        payload: &Payload,
+    ) -> crate::error::Result<Option<String>> {
+        let reply = self
+            .get_response(&payload.generators, &payload.tokenizer)
+            .await?;
+        let reply = reply.trim();
+        if reply.is_empty() {
+            eprintln!("GPT2 Generated an empty response, try again.");
+            return Ok(None);
+        }
+        self.record(super::reply_line(reply.to_string()), &payload.tokenizer)?;
+        Ok(Some(reply.to_string()))
+    }
+}
+
+/// Cuts `text` at the first occurrence of any stop token, mirroring the `stop` parameter of the
+/// GPT3 API
+fn truncate_at_stop_tokens<'a>(text: &'a str, stop_tokens: &[String]) -> &'a str {
+    let end = stop_tokens
+        .iter()
+        .filter(|token| !token.is_empty())
+        .filter_map(|token| text.find(&**token))
+        .min()
+        .unwrap_or_else(|| text.len());
+    &text[..end]
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Configuration {
+    /// Directory holding `model.ot`, `config.json`, `vocab.json` and `merges.txt`
+    pub model_dir: PathBuf,
+    /// Upper bound on prompt + generated tokens
+    pub max_length: u64,
+    pub do_sample: bool,
+    pub temperature: f64,
+    pub top_k: u64,
+    pub top_p: f64,
+    pub repetition_penalty: f64,
+}
+
+impl Default for Configuration {
+    fn default() -> Configuration {
+        Configuration {
+            model_dir: default_model_dir(),
+            max_length: 1024,
+            do_sample: true,
+            temperature: 1.0,
+            top_k: 50,
+            top_p: 1.0,
+            repetition_penalty: 1.0,
+        }
+    }
+}
+
+impl Configuration {
+    fn generate_config(&self) -> GenerateConfig {
+        let resource = |file_name| {
+            Resource::Local(LocalResource {
+                local_path: self.model_dir.join(file_name),
+            })
+        };
+        GenerateConfig {
+            model_resource: resource("model.ot"),
+            config_resource: resource("config.json"),
+            vocab_resource: resource("vocab.json"),
+            merges_resource: resource("merges.txt"),
+            max_length: self.max_length as i64,
+            do_sample: self.do_sample,
+            temperature: self.temperature,
+            top_k: self.top_k as i64,
+            top_p: self.top_p,
+            repetition_penalty: self.repetition_penalty,
+            num_beams: 1,
+            num_return_sequences: 1,
+            ..GenerateConfig::default()
         }
     }
+
+    /// Whether generators built for either configuration sample the same way. `max_length` is
+    /// given to every generation, so it doesn't count
+    fn samples_like(&self, other: &Configuration) -> bool {
+        self.do_sample == other.do_sample
+            && self.temperature == other.temperature
+            && self.top_k == other.top_k
+            && self.top_p == other.top_p
+            && self.repetition_penalty == other.repetition_penalty
+    }
 }
 
-#[derive(Debug)]
-pub struct Configuration {}
+fn default_model_dir() -> PathBuf {
+    std::env::var("GPT2_MODEL_DIR")
+        .unwrap_or_else(|_| String::from(DEFAULT_MODEL_DIR))
+        .into()
+}
+
+/// Finds the model called `model` in the models directory. Whoever picks a model in chat isn't
+/// trusted with the rest of the file system, so names can't point outside of it
+fn resolve_model_dir(model: &str) -> Result<PathBuf, StringError> {
+    let model = Path::new(model);
+    let is_name = model
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if model.as_os_str().is_empty() || !is_name {
+        return Err(StringError::from(
+            "GPT2 models are picked by their name in the models directory",
+        ));
+    }
+    let models_dir: PathBuf = std::env::var("GPT2_MODELS_DIR")
+        .unwrap_or_else(|_| String::from(DEFAULT_MODELS_DIR))
+        .into();
+    let model_dir = models_dir.join(model);
+    if !model_dir.is_dir() {
+        return Err(StringError::from("GPT2 model directory does not exist"));
+    }
+    Ok(model_dir)
+}
+
+/// Loaded models, one per model directory, shared between every session using it so we only
+/// pay for loading the weights once
+#[derive(Default)]
+pub struct GeneratorCache {
+    generators: Mutex<HashMap<PathBuf, LoadedGenerator>>,
+}
+
+struct LoadedGenerator {
+    /// What the generator was built with
+    configuration: Configuration,
+    generator: Arc<Mutex<GPT2Generator>>,
+}
+
+impl GeneratorCache {
+    async fn get(
+        &self,
+        configuration: &Configuration,
+    ) -> crate::error::Result<Arc<Mutex<GPT2Generator>>> {
+        let loaded = self
+            .lock()
+            .get(&configuration.model_dir)
+            .filter(|loaded| loaded.configuration.samples_like(configuration))
+            .map(|loaded| Arc::clone(&loaded.generator));
+        if let Some(generator) = loaded {
+            return Ok(generator);
+        }
+        // sampling settings are baked in when the generator is built, so new ones mean loading
+        // the model again. The map isn't held meanwhile, other models stay usable
+        let generate_config = configuration.generate_config();
+        let generator = tokio::task::spawn_blocking(move || GPT2Generator::new(generate_config))
+            .await
+            .map_err(|why| crate::error::Error::Generation(why.to_string()))?
+            .map_err(|why| crate::error::Error::Generation(why.to_string()))?;
+        let generator = Arc::new(Mutex::new(generator));
+        // replacing the old copy frees it once no generation is using it anymore
+        self.lock().insert(
+            configuration.model_dir.clone(),
+            LoadedGenerator {
+                configuration: configuration.clone(),
+                generator: Arc::clone(&generator),
+            },
+        );
+        Ok(generator)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, LoadedGenerator>> {
+        self.generators
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
 
 impl fmt::Display for GPT2MessageHandler {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -35,76 +408,206 @@ impl fmt::Display for GPT2MessageHandler {
     }
 }
 
+pub struct Payload {
+    pub channel_id: ChannelId,
+    pub generators: Arc<GeneratorCache>,
+    pub tokenizer: Arc<Tokenizer>,
+}
+
+/// On-disk form of a [`GPT2MessageHandler`]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SavedSession {
+    pub transformer: TransformerKind,
+    pub message_log: Vec<LogItem>,
+    pub configuration: Configuration,
+    #[serde(default)]
+    pub channel_settings: ChannelSettings,
+}
+
 #[serenity::async_trait]
 impl super::MessageSessionHandler for GPT2MessageHandler {
-    type Payload = i32;
+    type Payload = Payload;
 
-    async fn perform_work(&mut self, http: &serenity::http::Http, payload: Self::Payload) {}
-    async fn reset(&mut self, ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    async fn perform_work(
+        &mut self,
+        platform: &Arc<dyn ChatPlatform>,
+        payload: Self::Payload,
+    ) -> Option<LogItem> {
+        match self.generate_reply(&payload).await {
+            Ok(Some(reply)) => {
+                let message = serenity::utils::MessageBuilder::new()
+                    .push_safe(&reply)
+                    .build();
+                let message_id = match platform.send_message(payload.channel_id, &message).await {
+                    Ok(message_id) => {
+                        super::tag_last_reply(&mut self.message_log, message_id);
+                        Some(message_id)
+                    }
+                    Err(why) => {
+                        eprintln!("Failed to send message to {}", &why);
+                        None
+                    }
+                };
+                Some(LogItem {
+                    message_id,
+                    ..super::reply_line(reply)
+                })
+            }
+            Ok(None) => None,
+            Err(why) => {
+                eprintln!("Failed to generate GPT2 response: {}", &why);
+                if let Err(why) = platform
+                    .send_message(payload.channel_id, &format!("⚠️ {}", why.user_message()))
+                    .await
+                {
+                    eprintln!("Failed to report generation failure: {}", &why);
+                }
+                None
+            }
+        }
+    }
+
+    async fn reset(&mut self, ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+        self.clear();
+        msg.react(&ctx, '✅').await?;
         Ok(())
     }
-    async fn enable(ctx: &Context, msg: &Message, args: Args) -> Result<Session, CommandError> {
-        let (transformer, engine) = log_transformer_from_serenity_args(ctx, &mut args).await?;
+
+    async fn enable(ctx: &Context, msg: &Message, mut args: Args) -> Result<Session, CommandError> {
+        let (transformer, model_dir) =
+            transformers::log_transformer_from_serenity_args(ctx, &mut args).await?;
         let mut handler = GPT2MessageHandler::new(transformer);
-        handler.set_engine(engine);
+        if let Some(model) = model_dir {
+            handler.set_model_dir(resolve_model_dir(&model)?);
+        }
+        if !handler.configuration.model_dir.is_dir() {
+            return Err(StringError::from("GPT2 model directory does not exist").into());
+        }
 
         msg.react(&ctx, '✅').await?;
+        Ok(Session::GPT2(handler))
+    }
 
-        Ok(Session::GPT2(GPT2MessageHandler {}))
+    fn tune(&mut self, tuning: Tuning) -> Result<(), StringError> {
+        let config = &mut self.configuration;
+        match tuning {
+            Tuning::Temperature(temperature) => config.temperature = temperature,
+            Tuning::TopP(top_p) => config.top_p = top_p,
+            Tuning::Engine(model) => self.set_model_dir(resolve_model_dir(&model)?),
+            Tuning::Context(context) => self.transformer.set_context(&context),
+            Tuning::Trigger(trigger) => self.channel_settings.trigger = trigger,
+            Tuning::Listen(listen) => self.channel_settings.listen = listen,
+            Tuning::Delay(delay, millis) => self.channel_settings.timing.set(delay, millis),
+            Tuning::PresencePenalty(_)
+            | Tuning::FrequencyPenalty(_)
+            | Tuning::MaxTokens(_)
+            | Tuning::MaxRetries(_)
+            | Tuning::RequestMode(_)
+            | Tuning::Streaming(_)
+            | Tuning::Summarizer(_) => {
+                return Err(StringError::from("That setting is not supported by GPT2"));
+            }
+        }
+        Ok(())
     }
-    async fn info(&self, ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+
+    async fn info(&self, ctx: &Context, msg: &Message, _args: Args) -> CommandResult {
+        let config = &self.configuration;
+        if let Err(why) = msg
+            .channel_id
+            .send_message(&ctx, |c_m| {
+                c_m.embed(|e| {
+                    let mut e = e
+                        .field("model", config.model_dir.display(), true)
+                        .field("temperature", config.temperature.to_string(), true)
+                        .field("top_k", config.top_k.to_string(), true)
+                        .field("top_p", config.top_p.to_string(), true)
+                        .field(
+                            "repetition_penalty",
+                            config.repetition_penalty.to_string(),
+                            true,
+                        )
+                        .field("lines", self.message_log.len().to_string(), true)
+                        .field("trigger", self.channel_settings.to_string(), true)
+                        .field("timing", self.channel_settings.timing.to_string(), true);
+                    if let Some(context) = self.transformer.get_context() {
+                        e = e.description(context.clone());
+                    }
+                    e
+                })
+            })
+            .await
+        {
+            eprintln!("Failed to send info embed: {:?}", &why);
+        }
         Ok(())
     }
 }
 
-pub async fn log_transformer_from_serenity_args(
-    context: &serenity::prelude::Context,
-    args: &mut serenity::framework::standard::Args,
-) -> Result<(TransformerKind, String), crate::commands::StringError> {
-    // 0th, the engine
-    let engine = {
-        if let Ok(arg) = args.single::<String>() {
-            match &*arg.to_lowercase() {
-                "default" => String::from("davinci"),
-                _ => arg,
-            }
-        } else {
-            return Err("Missing engine (if you aren't sure, use `default`)".into());
-        }
-    };
-    // first, the transform type
-    let transform_type = args.single::<String>()?.to_lowercase();
-    Ok(match &*transform_type {
-        // if we get single, then get the ai name (otherwise, default to the bots name)
-        "conversation" | "convo" => {
-            let bot_name = context.http.get_current_application_info().await?.name;
-            let ai_name = {
-                let temp = args
-                    .single_quoted()
-                    // it's ok to do this since I don't expet this method to be called frequently
-                    .unwrap_or_else(|_| bot_name.clone());
-                if temp == "_" {
-                    bot_name
-                } else {
-                    temp
-                }
-            };
-            let context = {
-                let rest = args.rest();
-                let trimmed = rest.trim().trim_matches('`').trim_matches('"').trim();
-                if trimmed.is_empty() {
-                    None
-                } else {
-                    Some(trimmed.to_string())
-                }
-            }
-            .map(|context| context.replace("{name}", &*ai_name));
-            dbg!(&context);
-            (
-                TransformerKind::Conversation(conversation::Transformer { ai_name, context }),
-                engine,
-            )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_at_earliest_stop_token() {
+        let stop_tokens = vec![String::from("\n"), String::from("User ")];
+        assert_eq!(
+            truncate_at_stop_tokens(" hi there User (foo): hey\n", &stop_tokens),
+            " hi there "
+        );
+        assert_eq!(truncate_at_stop_tokens(" hi there", &stop_tokens), " hi there");
+    }
+
+    #[test]
+    fn models_are_only_found_in_the_models_directory() {
+        for model in &["", "../secrets", "gpt2/../../etc", "/etc", "./gpt2"] {
+            assert!(
+                resolve_model_dir(model).is_err(),
+                "{:?} should be rejected",
+                model
+            );
         }
-        _ => return Err("Invalid conversation type".into()),
-    })
+    }
+
+    #[test]
+    fn long_logs_are_trimmed_from_the_oldest_line() {
+        let tokenizer = crate::tokenizer::test_tokenizer();
+        let mut session = GPT2MessageHandler::new(TransformerKind::Conversation(
+            transformers::conversation::Transformer {
+                ai_name: String::from("Ai"),
+                context: None,
+                summary: None,
+            },
+        ));
+        for text in &["first", "second", "third"] {
+            session
+                .record(
+                    LogItem {
+                        author_name: Some(String::from("foo")),
+                        author_nick: None,
+                        text: text.to_string(),
+                        sent_by_ai: false,
+                        message_id: None,
+                    },
+                    &tokenizer,
+                )
+                .expect("Recording a line should not fail");
+        }
+        assert_eq!(session.message_log.len(), 3);
+        let full = tokenizer.count(&session.make_prompt().unwrap());
+        let (prompt, prompt_tokens) = session
+            .make_trimmed_prompt(&tokenizer, full - 1)
+            .expect("Dropping lines makes it fit");
+        assert!(prompt_tokens < full);
+        assert!(!prompt.contains("first"));
+        assert!(prompt.contains("third"));
+        assert!(session.make_trimmed_prompt(&tokenizer, 0).is_err());
+
+        session.configuration.max_length = 0;
+        session.ensure_is_safe(&tokenizer).unwrap();
+        assert!(
+            session.message_log.is_empty(),
+            "Lines that can't fit are dropped"
+        );
+    }
 }
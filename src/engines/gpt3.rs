@@ -1,26 +1,303 @@
 /// This file is the preferred interface for remote GPT3
+use super::{
+    openai::{
+        ChatCompletion, ChatCompletionParameters, ChatMessage, Completion, CompletionProvider,
+        CompletionStream, FinishReason, Role,
+    },
+    summarization::LocalSummarizer,
+    Tuning,
+};
 use crate::{
+    channel::ChannelSettings,
+    commands::StringError,
+    platform::ChatPlatform,
+    tokenizer::Tokenizer,
     transformers::{
         self,
         conversation::{self, LogItem},
-        LogTransformer,
+        TransformerKind,
     },
+    usage::Tally,
     Session,
 };
 use serenity::{
     framework::standard::{Args, CommandError, CommandResult},
-    model::{channel::Message, id::ChannelId},
+    model::{
+        channel::Message,
+        id::{ChannelId, MessageId},
+    },
     prelude::Context,
+    utils::MessageBuilder,
 };
 
-use std::fmt;
-// const GPT_MAX_TOKEN_LEN: usize = 2_049;
+use futures::StreamExt;
+use rand::Rng;
+use std::{
+    fmt,
+    fmt::Write,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::mpsc, time};
+
+pub use super::openai::CompletionParameters;
+
+const GPT_MAX_TOKEN_LEN: usize = 2_049;
+pub const DEFAULT_ENGINE: &str = "davinci";
+/// What a session switches to when it's put in chat mode with a text engine
+pub const DEFAULT_CHAT_ENGINE: &str = "gpt-3.5-turbo";
+/// Keeps the rolling summary from eating the budget it's meant to save
+const SUMMARY_MAX_TOKENS: usize = 128;
+/// Discord shows typing for ~10 seconds after each broadcast
+const TYPING_INTERVAL: Duration = Duration::from_secs(8);
+/// Role and separator tokens the chat format wraps around each message
+const CHAT_MESSAGE_OVERHEAD: usize = 4;
+/// Trimmed lines waiting on a summary past this many are forgotten, so a summarizer that keeps
+/// failing isn't sent an ever growing transcript
+const MAX_UNSUMMARIZED_LINES: usize = 40;
+/// What a streamed reply shows until the first tokens arrive
+const STREAM_PLACEHOLDER: &str = "…";
+/// Discord allows about five edits to a message every five seconds
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1_500);
+
+/// Receives the whole reply so far every time a streamed completion grows
+type Progress = mpsc::UnboundedSender<String>;
+
+/// Context sizes by engine name prefix, so dated snapshots like `gpt-4-0613` are covered. Longer
+/// prefixes come first, `gpt-4-32k` isn't a `gpt-4`
+const CONTEXT_SIZES: &[(&str, usize)] = &[
+    ("gpt-3.5-turbo-16k", 16_384),
+    ("gpt-3.5-turbo", 4_096),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("davinci-codex", 4_096),
+    ("cushman-codex", 4_096),
+];
+/// Engine name prefixes only served by `/chat/completions`
+const CHAT_ENGINES: &[&str] = &["gpt-3.5-turbo", "gpt-4"];
+
+/// Tokens an engine can attend to, prompt and completion combined
+fn context_size(engine: &str) -> usize {
+    CONTEXT_SIZES
+        .iter()
+        .find(|(prefix, _)| engine.starts_with(prefix))
+        .map_or(GPT_MAX_TOKEN_LEN, |(_, size)| *size)
+}
+
+/// Whether `engine` is only served by `/chat/completions`
+fn is_chat_engine(engine: &str) -> bool {
+    CHAT_ENGINES.iter().any(|prefix| engine.starts_with(prefix))
+}
+
+/// Text engines come with `gpt3`, chat engines cost more and have to be allowed by name (prefix)
+fn engine_allowed(engine: &str, rules: &crate::config::EngineRules) -> bool {
+    !is_chat_engine(engine)
+        || rules
+            .engines
+            .iter()
+            .any(|allowed| engine.starts_with(allowed.as_str()))
+}
+
+/// Which completions API a session talks to
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestMode {
+    /// `/engines/{engine}/completions`, with the log flattened into one prompt
+    Text,
+    /// `/chat/completions`, with each log line as a role message
+    Chat,
+}
+
+impl Default for RequestMode {
+    fn default() -> RequestMode {
+        RequestMode::Text
+    }
+}
+
+impl RequestMode {
+    /// The only mode `engine` works in
+    pub fn for_engine(engine: &str) -> RequestMode {
+        if is_chat_engine(engine) {
+            RequestMode::Chat
+        } else {
+            RequestMode::Text
+        }
+    }
+
+    fn default_engine(self) -> &'static str {
+        match self {
+            RequestMode::Text => DEFAULT_ENGINE,
+            RequestMode::Chat => DEFAULT_CHAT_ENGINE,
+        }
+    }
+}
+
+impl std::str::FromStr for RequestMode {
+    type Err = StringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "text" => Ok(RequestMode::Text),
+            "chat" => Ok(RequestMode::Chat),
+            _ => Err(format!("Unknown mode `{}`, expected `text` or `chat`", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for RequestMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestMode::Text => write!(f, "text"),
+            RequestMode::Chat => write!(f, "chat"),
+        }
+    }
+}
 
 pub struct GPT3MessageHandler {
+    pub provider: Arc<dyn CompletionProvider>,
     pub transformer: TransformerKind,
     pub message_log: Vec<LogItem>,
     pub configuration: CompletionParameters,
+    pub budget: TokenBudget,
+    /// Lines trimmed from `message_log` that haven't been folded into the summary yet
+    pub unsummarized: Vec<LogItem>,
+    pub retry_policy: RetryPolicy,
+    pub request_mode: RequestMode,
+    /// Post a placeholder right away and edit the reply into it as it's generated
+    pub stream_replies: bool,
+    pub channel_settings: ChannelSettings,
     pub token_count: usize,
+    /// How many lines `ensure_is_safe` has dropped from the front of `message_log`, which tells
+    /// what a [`Snapshot`] trimmed
+    trimmed_lines: usize,
+    /// Spent on requests since the dispatcher last collected it with `take_usage`
+    usage: Mutex<Tally>,
+}
+
+/// A copy of a session to generate a reply from, so the session itself stays free for new lines
+/// and commands in the meantime. [`GPT3MessageHandler::commit`] takes the outcome back in
+pub struct Snapshot {
+    pub handler: GPT3MessageHandler,
+    /// How the session looked when the copy was made
+    message_log: Vec<LogItem>,
+    unsummarized: Vec<LogItem>,
+    summary: Option<String>,
+    trimmed_lines: usize,
+}
+
+/// Capped exponential backoff with full jitter, for failures that might go away on their own
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 8_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt` (starting at 0). A `Retry-After` from the
+    /// API wins if it asks for longer than the backoff would, but never past `max_delay_ms`
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let max_delay = Duration::from_millis(self.max_delay_ms);
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1_u64 << attempt.min(32))
+            .min(self.max_delay_ms);
+        let jittered = Duration::from_millis(rand::thread_rng().gen_range(0, backoff + 1));
+        retry_after.map_or(jittered, |retry_after| {
+            retry_after.min(max_delay).max(jittered)
+        })
+    }
+}
+
+/// How trimmed lines get condensed into the transformer's summary
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Summarizer {
+    /// Trimmed lines are forgotten
+    Off,
+    /// A summarization prompt against the session's own engine
+    Engine,
+    /// The rust-bert summarization pipeline
+    Local,
+}
+
+impl std::str::FromStr for Summarizer {
+    type Err = StringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "off" => Ok(Summarizer::Off),
+            "engine" => Ok(Summarizer::Engine),
+            "local" => Ok(Summarizer::Local),
+            _ => Err(format!(
+                "Unknown summarizer `{}`, expected `off`, `engine` or `local`",
+                s
+            )
+            .into()),
+        }
+    }
+}
+
+impl fmt::Display for Summarizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Summarizer::Off => write!(f, "off"),
+            Summarizer::Engine => write!(f, "engine"),
+            Summarizer::Local => write!(f, "local"),
+        }
+    }
+}
+
+/// How much of an engine's context a session may spend on its prompt
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TokenBudget {
+    /// Caps the prompt below what the engine could take, to keep requests cheap
+    pub max_prompt_tokens: Option<usize>,
+    /// Held back for the completion when `max_tokens` isn't configured
+    pub reserved_completion_tokens: usize,
+    /// The most recent log lines, which are never trimmed
+    pub keep_last_turns: usize,
+    pub summarizer: Summarizer,
+}
+
+impl Default for TokenBudget {
+    fn default() -> TokenBudget {
+        TokenBudget {
+            max_prompt_tokens: None,
+            reserved_completion_tokens: 150,
+            keep_last_turns: 4,
+            // summaries cost a request of their own, so sessions opt into them
+            summarizer: Summarizer::Off,
+        }
+    }
+}
+
+impl TokenBudget {
+    pub fn completion_tokens(&self, configuration: &CompletionParameters) -> usize {
+        configuration
+            .max_tokens
+            .unwrap_or(self.reserved_completion_tokens)
+    }
+
+    pub fn prompt_tokens(&self, configuration: &CompletionParameters) -> usize {
+        let available = context_size(&configuration.engine)
+            .saturating_sub(self.completion_tokens(configuration));
+        self.max_prompt_tokens
+            .map_or(available, |max_prompt_tokens| max_prompt_tokens.min(available))
+    }
 }
 
 impl fmt::Display for GPT3MessageHandler {
@@ -34,13 +311,25 @@ impl fmt::Display for GPT3MessageHandler {
 }
 
 impl GPT3MessageHandler {
-    pub fn new(transformer: TransformerKind) -> GPT3MessageHandler {
-        let configuration = transformer.default_configuration();
+    pub fn new(
+        transformer: TransformerKind,
+        provider: Arc<dyn CompletionProvider>,
+    ) -> GPT3MessageHandler {
+        let configuration = transformer.default_gpt3_configuration();
         GPT3MessageHandler {
+            provider,
             transformer,
             message_log: Vec::new(),
             configuration,
+            budget: TokenBudget::default(),
+            unsummarized: Vec::new(),
+            retry_policy: RetryPolicy::default(),
+            request_mode: RequestMode::default(),
+            stream_replies: false,
+            channel_settings: ChannelSettings::default(),
             token_count: 0,
+            trimmed_lines: 0,
+            usage: Mutex::default(),
         }
     }
 
@@ -48,9 +337,240 @@ impl GPT3MessageHandler {
         self.configuration.engine = engine;
     }
 
-    pub async fn record(&mut self, log_item: LogItem, gpt_token: &str) -> crate::error::Result<()> {
+    pub fn apply_limits(&mut self, rules: &crate::config::EngineRules) {
+        if let Some(limit) = rules.max_prompt_tokens {
+            self.budget.max_prompt_tokens = Some(
+                self.budget
+                    .max_prompt_tokens
+                    .map_or(limit, |max_prompt_tokens| max_prompt_tokens.min(limit)),
+            );
+        }
+        if let Some(limit) = rules.max_tokens {
+            self.configuration.max_tokens = Some(
+                self.configuration
+                    .max_tokens
+                    .map_or(limit, |max_tokens| max_tokens.min(limit)),
+            );
+            self.budget.reserved_completion_tokens =
+                self.budget.reserved_completion_tokens.min(limit);
+        }
+        if !engine_allowed(&self.configuration.engine, rules) {
+            self.request_mode = RequestMode::Text;
+            self.set_engine(RequestMode::Text.default_engine().to_string());
+        }
+    }
+
+    /// Refuses `tuning` if it would take the session past `rules`, see [`GPT3MessageHandler::apply_limits`]
+    pub fn check_limits(
+        &self,
+        tuning: &Tuning,
+        rules: &crate::config::EngineRules,
+    ) -> Result<(), StringError> {
+        let engine = match tuning {
+            Tuning::MaxTokens(max_tokens) => {
+                return match rules.max_tokens {
+                    Some(limit) if *max_tokens > limit => {
+                        Err(format!("max_tokens is limited to {} here", limit).into())
+                    }
+                    _ => Ok(()),
+                };
+            }
+            Tuning::Engine(engine) => engine.as_str(),
+            // switching modes also switches to the mode's default engine
+            Tuning::RequestMode(request_mode)
+                if RequestMode::for_engine(&self.configuration.engine) != *request_mode =>
+            {
+                request_mode.default_engine()
+            }
+            _ => return Ok(()),
+        };
+        if engine_allowed(engine, rules) {
+            Ok(())
+        } else {
+            Err(format!("{} isn't allowed here", engine).into())
+        }
+    }
+
+    /// Copies out everything needed to rebuild this handler after a restart
+    pub fn save(&self) -> SavedSession {
+        SavedSession {
+            engine: self.configuration.engine.clone(),
+            transformer: self.transformer.clone(),
+            message_log: self.message_log.clone(),
+            configuration: self.configuration.clone(),
+            budget: self.budget.clone(),
+            unsummarized: self.unsummarized.clone(),
+            retry_policy: self.retry_policy.clone(),
+            request_mode: self.request_mode,
+            stream_replies: self.stream_replies,
+            channel_settings: self.channel_settings.clone(),
+            token_count: self.token_count,
+        }
+    }
+
+    pub fn restore(
+        saved: SavedSession,
+        provider: Arc<dyn CompletionProvider>,
+    ) -> GPT3MessageHandler {
+        let mut handler = GPT3MessageHandler {
+            provider,
+            transformer: saved.transformer,
+            message_log: saved.message_log,
+            configuration: saved.configuration,
+            budget: saved.budget,
+            unsummarized: saved.unsummarized,
+            retry_policy: saved.retry_policy,
+            request_mode: saved.request_mode,
+            stream_replies: saved.stream_replies,
+            channel_settings: saved.channel_settings,
+            token_count: saved.token_count,
+            trimmed_lines: 0,
+            usage: Mutex::default(),
+        };
+        // the engine is never serialized as part of the request body, so it's kept separately
+        handler.set_engine(saved.engine);
+        handler
+    }
+
+    /// Copies the session out to generate a reply from
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            handler: GPT3MessageHandler {
+                provider: Arc::clone(&self.provider),
+                transformer: self.transformer.clone(),
+                message_log: self.message_log.clone(),
+                configuration: self.configuration.clone(),
+                budget: self.budget.clone(),
+                unsummarized: self.unsummarized.clone(),
+                retry_policy: self.retry_policy.clone(),
+                request_mode: self.request_mode,
+                stream_replies: self.stream_replies,
+                channel_settings: self.channel_settings.clone(),
+                token_count: self.token_count,
+                trimmed_lines: self.trimmed_lines,
+                usage: Mutex::default(),
+            },
+            message_log: self.message_log.clone(),
+            unsummarized: self.unsummarized.clone(),
+            summary: self.transformer.get_summary().clone(),
+            trimmed_lines: self.trimmed_lines,
+        }
+    }
+
+    /// Takes in `reply` and what generating it from `snapshot` spent. Lines recorded meanwhile
+    /// are kept. The snapshot's trimming and summary only carry over if the lines involved
+    /// weren't touched meanwhile, otherwise they're trimmed again here
+    pub fn commit(
+        &mut self,
+        mut snapshot: Snapshot,
+        reply: Option<LogItem>,
+        tokenizer: &Tokenizer,
+    ) {
+        let trimmed = snapshot.handler.trimmed_lines - snapshot.trimmed_lines;
+        let trimmed_from_log = trimmed.min(snapshot.message_log.len());
+        let untouched = self
+            .message_log
+            .starts_with(&snapshot.message_log[..trimmed_from_log])
+            && self.unsummarized == snapshot.unsummarized
+            && *self.transformer.get_summary() == snapshot.summary
+            && self.budget.summarizer == snapshot.handler.budget.summarizer;
+        if untouched {
+            self.message_log.drain(..trimmed_from_log);
+            self.trimmed_lines += trimmed_from_log;
+            self.unsummarized = std::mem::take(&mut snapshot.handler.unsummarized);
+            self.transformer
+                .set_summary(snapshot.handler.transformer.get_summary().clone());
+        }
+        if let Some(reply) = reply {
+            // the snapshot may have trimmed the reply itself, into what was just taken over
+            if !untouched || trimmed <= snapshot.message_log.len() {
+                self.message_log.push(reply);
+            }
+        }
+        let spent = snapshot.handler.take_usage();
+        self.usage.get_mut().unwrap().add(&spent);
+        if let Err(why) = self.ensure_is_safe(tokenizer) {
+            eprintln!("Failed to trim chat log after a reply: {}", &why);
+        }
+    }
+
+    /// Hands over what was spent on requests since the last call
+    pub fn take_usage(&mut self) -> Tally {
+        std::mem::take(self.usage.get_mut().unwrap())
+    }
+
+    /// Notes down a request that went through
+    fn spend(&self, prompt_tokens: usize, completion_tokens: usize) {
+        self.usage.lock().unwrap().add(&Tally {
+            requests: 1,
+            prompt_tokens: prompt_tokens as u64,
+            completion_tokens: completion_tokens as u64,
+        });
+    }
+
+    /// Forgets the conversation, keeping the settings
+    pub fn clear(&mut self) {
+        self.message_log.clear();
+        self.unsummarized.clear();
+        self.transformer.set_summary(None);
+    }
+
+    pub fn record(
+        &mut self,
+        log_item: LogItem,
+        tokenizer: &Tokenizer,
+    ) -> crate::error::Result<()> {
         self.message_log.push(log_item);
-        self.update_token_count(gpt_token).await
+        self.update_token_count(tokenizer)
+    }
+
+    /// Takes the most recent reply out of the log
+    pub fn undo(&mut self, tokenizer: &Tokenizer) -> Option<LogItem> {
+        let reply = super::remove_last_reply(&mut self.message_log);
+        self.recount_tokens(tokenizer);
+        reply
+    }
+
+    /// Takes the last `count` lines out of the log. Lines that were already trimmed for the
+    /// summary aren't touched
+    pub fn forget(&mut self, count: usize, tokenizer: &Tokenizer) -> Vec<LogItem> {
+        let forgotten = super::remove_last_lines(&mut self.message_log, count);
+        self.recount_tokens(tokenizer);
+        forgotten
+    }
+
+    /// Updates or drops the line someone sent as `message_id`, returning whether there was one.
+    /// Lines waiting to be summarized are updated too
+    pub fn edit_line(
+        &mut self,
+        message_id: MessageId,
+        text: Option<&str>,
+        tokenizer: &Tokenizer,
+    ) -> bool {
+        let edited = super::edit_line(&mut self.message_log, message_id, text)
+            || super::edit_line(&mut self.unsummarized, message_id, text);
+        if edited {
+            self.recount_tokens(tokenizer);
+        }
+        edited
+    }
+
+    /// Drops the lines for `message_ids`, including ones waiting to be summarized. Returns how
+    /// many there were
+    pub fn remove_lines(&mut self, message_ids: &[MessageId], tokenizer: &Tokenizer) -> usize {
+        let removed = super::remove_lines(&mut self.message_log, message_ids)
+            + super::remove_lines(&mut self.unsummarized, message_ids);
+        if removed > 0 {
+            self.recount_tokens(tokenizer);
+        }
+        removed
+    }
+
+    /// A stale count only shows up in `info`, so failing to update it isn't worth surfacing
+    fn recount_tokens(&mut self, tokenizer: &Tokenizer) {
+        if let Err(why) = self.update_token_count(tokenizer) {
+            eprintln!("Failed to recount tokens: {}", &why);
+        }
     }
 
     pub fn make_string(&self) -> Result<String, std::fmt::Error> {
@@ -62,23 +582,28 @@ impl GPT3MessageHandler {
         Ok(buf)
     }
 
-    pub async fn update_token_count(&mut self, gpt_token: &str) -> crate::error::Result<()> {
-        let current_state = self.make_string()?;
-        self.update_token_count_raw(gpt_token, current_state).await
+    pub fn update_token_count(&mut self, tokenizer: &Tokenizer) -> crate::error::Result<()> {
+        self.token_count = match self.request_mode {
+            RequestMode::Text => tokenizer.count(&self.make_string()?),
+            RequestMode::Chat => self.count_prompt_tokens(tokenizer)?,
+        };
+        Ok(())
     }
 
-    pub async fn update_token_count_raw(
-        &mut self,
-        gpt_token: &str,
-        text: String,
-    ) -> crate::error::Result<()> {
-        if let Some(token_count) = count_tokens(gpt_token, text, self.configuration.clone())
-            .await
-            .map_err(|s_err| crate::error::Error::Surf(s_err.to_string()))?
-        {
-            self.token_count = token_count;
-        }
-        Ok(())
+    /// Tokens the next request's prompt will take up, however it's sent
+    pub fn count_prompt_tokens(&self, tokenizer: &Tokenizer) -> crate::error::Result<usize> {
+        Ok(match self.request_mode {
+            RequestMode::Text => tokenizer.count(&self.make_prompt(None)?),
+            RequestMode::Chat => self
+                .make_chat_messages()?
+                .iter()
+                .map(|message| CHAT_MESSAGE_OVERHEAD + tokenizer.count(&message.content))
+                .sum(),
+        })
+    }
+
+    pub fn make_chat_messages(&self) -> Result<Vec<ChatMessage>, std::fmt::Error> {
+        self.transformer.chat_messages(&self.message_log)
     }
 
     pub fn make_prompt(
@@ -97,27 +622,215 @@ impl GPT3MessageHandler {
         self.transformer.get_stop_params()
     }
 
-    pub async fn ensure_is_safe(&mut self, gpt_token: &str) -> crate::error::Result<()> {
-        // 500 token hard cap
-        // TODO(haze): rethink about
-        while self.token_count > 500 {
-            self.message_log.drain(0..self.message_log.len() / 2);
-            self.update_token_count(gpt_token).await?;
+    /// Drops the oldest log lines one at a time until the prompt fits the session's budget. The
+    /// context lives in the transformer, so it is never trimmed, and neither are the last
+    /// `keep_last_turns` lines. Dropped lines are queued up for [`Self::update_summary`]
+    pub fn ensure_is_safe(&mut self, tokenizer: &Tokenizer) -> crate::error::Result<()> {
+        let budget = self.budget.prompt_tokens(&self.configuration);
+        // measured against the full prompt, since that's what is actually sent
+        let mut prompt_tokens = self.count_prompt_tokens(tokenizer)?;
+        let mut trimmed = 0;
+        while prompt_tokens > budget && self.message_log.len() > self.budget.keep_last_turns {
+            let dropped = self.message_log.remove(0);
+            if self.budget.summarizer != Summarizer::Off {
+                self.unsummarized.push(dropped);
+            }
+            trimmed += 1;
+            prompt_tokens = self.count_prompt_tokens(tokenizer)?;
+        }
+        self.trimmed_lines += trimmed;
+        let overflow = self
+            .unsummarized
+            .len()
+            .saturating_sub(MAX_UNSUMMARIZED_LINES);
+        self.unsummarized.drain(..overflow);
+        self.update_token_count(tokenizer)?;
+        if trimmed > 0 {
             println!(
-                "Token count after trimming: {}, {} log lines",
-                self.token_count,
+                "Trimmed {} log lines, prompt is now {}/{} tokens over {} lines",
+                trimmed,
+                prompt_tokens,
+                budget,
                 self.message_log.len()
             );
         }
+        if prompt_tokens > budget {
+            eprintln!(
+                "Prompt is still over budget ({}/{}) after trimming",
+                prompt_tokens, budget
+            );
+        }
         Ok(())
     }
 
-    /// Performs a GPT3 completion
+    /// Folds any trimmed lines into the transformer's summary. Lines stay queued if summarizing
+    /// fails, so they can be picked up on the next attempt
+    pub async fn update_summary(
+        &mut self,
+        local_summarizer: &LocalSummarizer,
+        tokenizer: &Tokenizer,
+    ) -> crate::error::Result<()> {
+        if self.unsummarized.is_empty() {
+            return Ok(());
+        }
+        let mut transcript = String::new();
+        for log_item in &self.unsummarized {
+            self.transformer.transform(&mut transcript, log_item)?;
+        }
+        let previous_summary = self.transformer.get_summary().clone();
+        let summary = match self.budget.summarizer {
+            Summarizer::Off => None,
+            Summarizer::Engine => {
+                self.summarize_with_engine(previous_summary.as_deref(), &transcript, tokenizer)
+                    .await?
+            }
+            Summarizer::Local => {
+                let mut text = previous_summary.unwrap_or_default();
+                text.push('\n');
+                text.push_str(&transcript);
+                Some(local_summarizer.summarize(text).await?)
+            }
+        };
+        if let Some(summary) = summary {
+            let summary = summary.trim();
+            if !summary.is_empty() {
+                self.transformer.set_summary(Some(summary.to_string()));
+            }
+            self.unsummarized.clear();
+            self.update_token_count(tokenizer)?;
+        }
+        Ok(())
+    }
+
+    async fn summarize_with_engine(
+        &self,
+        previous_summary: Option<&str>,
+        transcript: &str,
+        tokenizer: &Tokenizer,
+    ) -> crate::error::Result<Option<String>> {
+        let mut prompt = String::new();
+        if let Some(context) = self.transformer.get_context() {
+            write!(prompt, "{}\n\n", context)?;
+        }
+        write!(
+            prompt,
+            "Summary of the conversation so far:\n{}\n\nNew lines:\n{}\nUpdated summary of the whole conversation, in a few sentences:\n",
+            previous_summary.unwrap_or("Nothing has happened yet."),
+            transcript
+        )?;
+        let prompt_tokens = tokenizer.count(&prompt);
+        if self.request_mode == RequestMode::Chat {
+            let completion = self
+                .create_chat_completion(ChatCompletionParameters {
+                    model: self.configuration.engine.clone(),
+                    messages: vec![ChatMessage::new(Role::User, prompt)],
+                    max_tokens: Some(SUMMARY_MAX_TOKENS),
+                    temperature: Some(0.3),
+                    n: Some(1),
+                    ..ChatCompletionParameters::default()
+                })
+                .await?;
+            let summary = completion
+                .choices
+                .into_iter()
+                .next()
+                .map(|choice| choice.message.content);
+            self.spend(
+                CHAT_MESSAGE_OVERHEAD + prompt_tokens,
+                summary
+                    .as_deref()
+                    .map_or(0, |summary| tokenizer.count(summary)),
+            );
+            return Ok(summary);
+        }
+        let completion = self
+            .create_completion(CompletionParameters {
+                prompt: Some(prompt),
+                max_tokens: Some(SUMMARY_MAX_TOKENS),
+                temperature: Some(0.3),
+                top_p: Some(1.0),
+                n: Some(1),
+                best_of: Some(1),
+                stop: Some(vec![String::from("\n\n")]),
+                engine: self.configuration.engine.clone(),
+                ..CompletionParameters::default()
+            })
+            .await?;
+        let summary = completion
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.text);
+        self.spend(
+            prompt_tokens,
+            summary
+                .as_deref()
+                .map_or(0, |summary| tokenizer.count(summary)),
+        );
+        Ok(summary)
+    }
+
+    /// Trims the log to the budget and summarizes what was trimmed. The summary grows the
+    /// prompt, so anything trimmed by the second pass is summarized on the next one
+    async fn compact(&mut self, payload: &Payload) -> crate::error::Result<()> {
+        self.ensure_is_safe(&payload.tokenizer)?;
+        self.update_summary(&payload.local_summarizer, &payload.tokenizer)
+            .await?;
+        self.ensure_is_safe(&payload.tokenizer)
+    }
+
+    async fn create_completion(
+        &self,
+        params: CompletionParameters,
+    ) -> crate::error::Result<Completion> {
+        self.with_retries(|| self.provider.create_completion(&params))
+            .await
+    }
+
+    async fn create_chat_completion(
+        &self,
+        params: ChatCompletionParameters,
+    ) -> crate::error::Result<ChatCompletion> {
+        self.with_retries(|| self.provider.create_chat_completion(&params))
+            .await
+    }
+
+    /// Runs `request` until it succeeds, retrying failures that might go away on their own
+    async fn with_retries<T, F, R>(&self, mut request: F) -> crate::error::Result<T>
+    where
+        F: FnMut() -> R,
+        R: Future<Output = crate::error::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(why) if why.is_retryable() && attempt < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay(attempt, why.retry_after());
+                    eprintln!(
+                        "Completion attempt {} failed ({}), retrying in {:?}",
+                        attempt + 1,
+                        &why,
+                        delay
+                    );
+                    time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Performs a GPT3 completion. With `progress`, the completion is streamed and the answer so
+    /// far is reported as it grows
     pub async fn get_response(
         &self,
-        gpt_token: &str,
         params: CompletionParameters,
+        tokenizer: &Tokenizer,
+        progress: Option<&Progress>,
     ) -> crate::error::Result<Option<String>> {
+        if self.request_mode == RequestMode::Chat {
+            return self.get_chat_response(params, tokenizer, progress).await;
+        }
         let mut answer_buf = String::new();
         loop {
             let prompt = self.make_prompt(if answer_buf.is_empty() {
@@ -126,42 +839,229 @@ impl GPT3MessageHandler {
                 Some(&answer_buf)
             })?;
             println!("\n---\n{}\n---\n", &*prompt);
-            let response = create_completion(
-                gpt_token,
-                CompletionParameters {
-                    prompt: Some(prompt),
-                    n: Some(1),
-                    best_of: Some(1),
-                    stop: self.get_stop_params(),
-                    ..params.clone()
-                },
-            )
-            .await
-            .map_err(|e| crate::error::Error::Surf(e.to_string()))?;
-            match response {
-                CompletionResponse::Success { choices, .. } => {
-                    if let Some(first_choice) = choices.first() {
-                        dbg!(&first_choice);
-                        answer_buf.push_str(&*first_choice.text);
-                        if let Some(FinishReason::Stop) = first_choice.finish_reason {
-                            return Ok(Some(answer_buf));
-                        }
+            let prompt_tokens = tokenizer.count(&prompt);
+            let request = CompletionParameters {
+                prompt: Some(prompt),
+                n: Some(1),
+                best_of: Some(1),
+                stop: self.get_stop_params(),
+                max_tokens: Some(self.budget.completion_tokens(&params)),
+                ..params.clone()
+            };
+            let (text, finish_reason) = match progress {
+                Some(progress) => {
+                    let stream = self
+                        .with_retries(|| self.provider.create_completion_stream(&request))
+                        .await?;
+                    collect_stream(stream, &answer_buf, progress).await?
+                }
+                None => {
+                    let completion = self.create_completion(request).await?;
+                    match completion.choices.into_iter().next() {
+                        Some(first_choice) => (first_choice.text, first_choice.finish_reason),
+                        None => return Ok(None),
                     }
                 }
-                CompletionResponse::Error {
-                    error: CompletionError { message, .. },
-                } => {
-                    eprintln!("Failed to create completion: {:?}", &message);
-                    return Ok(None);
+            };
+            self.spend(prompt_tokens, tokenizer.count(&text));
+            answer_buf.push_str(&text);
+            // only a completion that ran out of tokens has more to say
+            if finish_reason != Some(FinishReason::Length) {
+                return Ok(Some(answer_buf));
+            }
+        }
+    }
+
+    /// Chat models finish their turn on their own, so unlike text completions there's nothing to
+    /// continue
+    async fn get_chat_response(
+        &self,
+        params: CompletionParameters,
+        tokenizer: &Tokenizer,
+        progress: Option<&Progress>,
+    ) -> crate::error::Result<Option<String>> {
+        let messages = self.make_chat_messages()?;
+        let prompt_tokens = messages
+            .iter()
+            .map(|message| CHAT_MESSAGE_OVERHEAD + tokenizer.count(&message.content))
+            .sum();
+        let request = ChatCompletionParameters {
+            model: params.engine.clone(),
+            messages,
+            max_tokens: Some(self.budget.completion_tokens(&params)),
+            temperature: params.temperature,
+            top_p: params.top_p,
+            n: Some(1),
+            // every message is its own turn, so the text mode stops would only cut replies short
+            stop: None,
+            presence_penalty: params.presence_penalty,
+            frequency_penalty: params.frequency_penalty,
+            stream: None,
+        };
+        if let Some(progress) = progress {
+            let stream = self
+                .with_retries(|| self.provider.create_chat_completion_stream(&request))
+                .await?;
+            let (text, _) = collect_stream(stream, "", progress).await?;
+            self.spend(prompt_tokens, tokenizer.count(&text));
+            return Ok(Some(text));
+        }
+        let completion = self.create_chat_completion(request).await?;
+        let text = completion
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content);
+        self.spend(
+            prompt_tokens,
+            text.as_deref().map_or(0, |text| tokenizer.count(text)),
+        );
+        Ok(text)
+    }
+
+    /// Everything `perform_work` does short of talking to Discord: compacts the log, asks for a
+    /// completion and records it. Returns the line to send, if there is one
+    pub async fn generate_reply(
+        &mut self,
+        payload: &Payload,
+    ) -> crate::error::Result<Option<String>> {
+        self.generate(payload, None).await
+    }
+
+    /// Like `generate_reply`, but streams the completion and sends the reply so far to `progress`
+    /// as it grows. `progress` is closed as soon as the completion is done
+    pub async fn generate_streamed_reply(
+        &mut self,
+        payload: &Payload,
+        progress: Progress,
+    ) -> crate::error::Result<Option<String>> {
+        self.generate(payload, Some(progress)).await
+    }
+
+    async fn generate(
+        &mut self,
+        payload: &Payload,
+        progress: Option<Progress>,
+    ) -> crate::error::Result<Option<String>> {
+        // new lines may have pushed the prompt over budget since the last reply
+        if let Err(why) = self.compact(payload).await {
+            eprintln!("Failed to trim chat log before completion: {}", &why);
+        }
+        let response = self
+            .get_response(
+                self.configuration.clone(),
+                &payload.tokenizer,
+                progress.as_ref(),
+            )
+            .await?;
+        // the finished reply can go up while the log is compacted
+        drop(progress);
+        let reply = match response {
+            Some(reply) if !reply.trim().is_empty() => reply.trim().to_string(),
+            _ => {
+                eprintln!("GPT3 returned no response");
+                return Ok(None);
+            }
+        };
+        self.record(super::reply_line(reply.clone()), &payload.tokenizer)?;
+        if let Err(why) = self.compact(payload).await {
+            eprintln!(
+                "Failed to delete enough chat logs to ensure safe self: {}",
+                &why
+            );
+        }
+        Ok(Some(reply))
+    }
+
+    /// Like `perform_work`, but posts a placeholder right away and edits the reply into it as
+    /// the completion streams in
+    async fn perform_streamed_work(
+        &mut self,
+        platform: &Arc<dyn ChatPlatform>,
+        payload: Payload,
+    ) -> Option<LogItem> {
+        let channel_id = payload.channel_id;
+        let message_id = match platform.send_message(channel_id, STREAM_PLACEHOLDER).await {
+            Ok(message_id) => message_id,
+            Err(why) => {
+                eprintln!("Failed to send placeholder message: {}", &why);
+                return None;
+            }
+        };
+        let placeholder = Placeholder {
+            platform: Arc::clone(platform),
+            channel_id,
+            message_id,
+            kept: false,
+        };
+        let platform = &**platform;
+        let (progress, updates) = mpsc::unbounded_channel();
+        let (reply, shown) = futures::join!(
+            self.generate_streamed_reply(&payload, progress),
+            edit_progressively(platform, channel_id, message_id, updates)
+        );
+        placeholder.keep();
+        let mut recorded = None;
+        let result = match reply {
+            Ok(Some(reply)) => {
+                let message = MessageBuilder::new().push_safe(&reply).build();
+                super::tag_last_reply(&mut self.message_log, message_id);
+                recorded = Some(LogItem {
+                    message_id: Some(message_id),
+                    ..super::reply_line(reply)
+                });
+                if shown.as_ref() == Some(&message) {
+                    return recorded;
                 }
+                platform
+                    .edit_message(channel_id, message_id, &message)
+                    .await
+            }
+            Ok(None) => platform.delete_message(channel_id, message_id).await,
+            Err(why) => {
+                eprintln!("Failed to create completion: {}", &why);
+                platform
+                    .edit_message(
+                        channel_id,
+                        message_id,
+                        &format!("⚠️ {}", why.user_message()),
+                    )
+                    .await
             }
+        };
+        if let Err(why) = result {
+            eprintln!("Failed to finish streamed reply: {}", &why);
         }
+        recorded
     }
 }
 
 pub struct Payload {
-    pub token: String,
     pub channel_id: ChannelId,
+    pub tokenizer: Arc<Tokenizer>,
+    pub local_summarizer: Arc<LocalSummarizer>,
+}
+
+/// On-disk form of a [`GPT3MessageHandler`]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SavedSession {
+    pub engine: String,
+    pub transformer: TransformerKind,
+    pub message_log: Vec<LogItem>,
+    pub configuration: CompletionParameters,
+    #[serde(default)]
+    pub budget: TokenBudget,
+    #[serde(default)]
+    pub unsummarized: Vec<LogItem>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub request_mode: RequestMode,
+    #[serde(default)]
+    pub stream_replies: bool,
+    #[serde(default)]
+    pub channel_settings: ChannelSettings,
+    pub token_count: usize,
 }
 
 #[serenity::async_trait]
@@ -169,14 +1069,23 @@ impl super::MessageSessionHandler for GPT3MessageHandler {
     type Payload = Payload;
 
     async fn reset(&mut self, ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-        self.message_log.clear();
+        self.clear();
         msg.react(&ctx, '✅').await?;
         Ok(())
     }
 
     async fn enable(ctx: &Context, msg: &Message, mut args: Args) -> Result<Session, CommandError> {
-        let (transformer, engine) = log_transformer_from_serenity_args(ctx, &mut args).await?;
-        let mut handler = GPT3MessageHandler::new(transformer);
+        let (transformer, engine) =
+            transformers::log_transformer_from_serenity_args(ctx, &mut args).await?;
+        let provider = {
+            let data = ctx.data.read().await;
+            data.get::<crate::CompletionProviderKey>()
+                .cloned()
+                .ok_or_else(|| StringError::from("No completion provider configured"))?
+        };
+        let mut handler = GPT3MessageHandler::new(transformer, provider);
+        let engine = engine.unwrap_or_else(|| String::from(DEFAULT_ENGINE));
+        handler.request_mode = RequestMode::for_engine(&engine);
         handler.set_engine(engine);
 
         msg.react(&ctx, '✅').await?;
@@ -224,10 +1133,31 @@ impl super::MessageSessionHandler for GPT3MessageHandler {
                                 .unwrap_or_else(|| String::from("None")),
                             true,
                         )
-                        .field("tokens", self.token_count.to_string(), true);
+                        .field(
+                            "tokens",
+                            format!(
+                                "{}/{}",
+                                self.token_count,
+                                self.budget.prompt_tokens(config)
+                            ),
+                            true,
+                        )
+                        .field("retries", self.retry_policy.max_retries.to_string(), true)
+                        .field("mode", self.request_mode.to_string(), true)
+                        .field("summarizer", self.budget.summarizer.to_string(), true)
+                        .field(
+                            "streaming",
+                            if self.stream_replies { "on" } else { "off" },
+                            true,
+                        )
+                        .field("trigger", self.channel_settings.to_string(), true)
+                        .field("timing", self.channel_settings.timing.to_string(), true);
                     if let Some(context) = self.transformer.get_context() {
                         e = e.description(context.clone());
                     }
+                    if let Some(summary) = self.transformer.get_summary() {
+                        e = e.field("summary", summary.clone(), false);
+                    }
                     e
                 })
             })
@@ -238,322 +1168,827 @@ impl super::MessageSessionHandler for GPT3MessageHandler {
         Ok(())
     }
 
-    async fn perform_work(&mut self, http: &serenity::http::Http, payload: Self::Payload) {
-        match self
-            .get_response(&*payload.token, self.configuration.clone())
-            .await
-        {
-            Ok(Some(gpt3_response)) => {
-                let gpt3_response = gpt3_response.trim();
-                if gpt3_response.is_empty() {
-                    eprintln!("GPT33 Generated an empty response, try again.");
-                    return;
+    fn tune(&mut self, tuning: Tuning) -> Result<(), StringError> {
+        let config = &mut self.configuration;
+        match tuning {
+            Tuning::Temperature(temperature) => config.temperature = Some(temperature),
+            Tuning::TopP(top_p) => config.top_p = Some(top_p),
+            Tuning::PresencePenalty(penalty) => config.presence_penalty = Some(penalty),
+            Tuning::FrequencyPenalty(penalty) => config.frequency_penalty = Some(penalty),
+            Tuning::MaxTokens(max_tokens) => {
+                let context_size = context_size(&config.engine);
+                if max_tokens >= context_size {
+                    return Err(format!(
+                        "max_tokens must be less than {} for {}",
+                        context_size, config.engine
+                    )
+                    .into());
                 }
-                if let Err(why) = self
-                    .record(
-                        transformers::conversation::LogItem {
-                            author_name: None,
-                            author_nick: None,
-                            text: gpt3_response.to_string(),
-                            sent_by_ai: true,
-                        },
-                        &*payload.token,
+                config.max_tokens = Some(max_tokens);
+            }
+            Tuning::Engine(engine) => {
+                if RequestMode::for_engine(&engine) != self.request_mode {
+                    return Err(format!(
+                        "{} doesn't work in {} mode, change the mode first",
+                        engine, self.request_mode
                     )
-                    .await
-                {
-                    eprintln!("Failed to record line: {:?}", why);
-                } else {
-                    eprintln!("before token check token count: {}", self.token_count);
-                    if let Err(why) = self.ensure_is_safe(&*payload.token).await {
-                        eprintln!(
-                            "Failed to delete enough chat logs to ensure safe self: {}",
-                            &why
-                        );
+                    .into());
+                }
+                self.set_engine(engine);
+            }
+            Tuning::MaxRetries(max_retries) => self.retry_policy.max_retries = max_retries,
+            Tuning::RequestMode(request_mode) => {
+                // every engine only works with one of the APIs
+                if RequestMode::for_engine(&config.engine) != request_mode {
+                    self.set_engine(request_mode.default_engine().to_string());
+                }
+                self.request_mode = request_mode;
+            }
+            Tuning::Streaming(stream_replies) => self.stream_replies = stream_replies,
+            Tuning::Summarizer(summarizer) => {
+                if summarizer == Summarizer::Off {
+                    self.unsummarized.clear();
+                }
+                self.budget.summarizer = summarizer;
+            }
+            Tuning::Trigger(trigger) => self.channel_settings.trigger = trigger,
+            Tuning::Listen(listen) => self.channel_settings.listen = listen,
+            Tuning::Delay(delay, millis) => self.channel_settings.timing.set(delay, millis),
+            Tuning::Context(context) => self.transformer.set_context(&context),
+        }
+        Ok(())
+    }
+
+    async fn perform_work(
+        &mut self,
+        platform: &Arc<dyn ChatPlatform>,
+        payload: Self::Payload,
+    ) -> Option<LogItem> {
+        if self.stream_replies {
+            return self.perform_streamed_work(platform, payload).await;
+        }
+        let platform = &**platform;
+        // typing wears off long before a completion with a few retries finishes
+        let reply = tokio::select! {
+            reply = self.generate_reply(&payload) => reply,
+            _ = keep_typing(platform, payload.channel_id) => unreachable!("keep_typing never finishes"),
+        };
+        match reply {
+            Ok(Some(reply)) => {
+                let message = MessageBuilder::new().push_safe(&reply).build();
+                let message_id = match platform.send_message(payload.channel_id, &message).await {
+                    Ok(message_id) => {
+                        super::tag_last_reply(&mut self.message_log, message_id);
+                        Some(message_id)
                     }
-                    let mut message_builder = serenity::utils::MessageBuilder::new();
-                    let message = message_builder.push_safe(gpt3_response);
-                    if let Err(why) = payload
-                        .channel_id
-                        .send_message(&http, |m| m.content(message))
-                        .await
-                    {
+                    Err(why) => {
                         eprintln!("Failed to send message to {}", &why);
+                        None
                     }
-                }
-            }
-            Ok(None) => {
-                eprintln!("GPT3 returned no response");
+                };
+                Some(LogItem {
+                    message_id,
+                    ..super::reply_line(reply)
+                })
             }
+            Ok(None) => None,
             Err(why) => {
                 eprintln!("Failed to create completion: {}", &why);
+                if let Err(why) = platform
+                    .send_message(payload.channel_id, &format!("⚠️ {}", why.user_message()))
+                    .await
+                {
+                    eprintln!("Failed to report completion failure: {}", &why);
+                }
+                None
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum TransformerKind {
-    Conversation(conversation::Transformer),
+/// A streamed reply's placeholder message, deleted again if the reply is dropped before it's
+/// done (like when the session is disabled halfway through)
+struct Placeholder {
+    platform: Arc<dyn ChatPlatform>,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    kept: bool,
 }
 
-impl TransformerKind {
-    pub fn get_context(&self) -> &Option<String> {
-        match self {
-            TransformerKind::Conversation(convo) => &convo.context,
-        }
+impl Placeholder {
+    /// The reply got far enough to take care of the message itself
+    fn keep(mut self) {
+        self.kept = true;
     }
-    pub fn set_context(&mut self, context: &str) {
-        match self {
-            TransformerKind::Conversation(convo) => convo.context = Some(context.to_string()),
+}
+
+impl Drop for Placeholder {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
         }
+        let platform = Arc::clone(&self.platform);
+        let channel_id = self.channel_id;
+        let message_id = self.message_id;
+        tokio::spawn(async move {
+            if let Err(why) = platform.delete_message(channel_id, message_id).await {
+                eprintln!("Failed to delete abandoned placeholder: {}", &why);
+            }
+        });
     }
-    fn default_configuration(&self) -> CompletionParameters {
-        match self {
-            TransformerKind::Conversation(trans) => trans,
+}
+
+/// Reads a streamed completion to the end, sending `prefix` plus the text so far to `progress`
+/// after every chunk. A stream that breaks off after some text still counts, with what it got
+/// through
+async fn collect_stream(
+    mut stream: CompletionStream,
+    prefix: &str,
+    progress: &Progress,
+) -> crate::error::Result<(String, Option<FinishReason>)> {
+    let mut text = String::new();
+    let mut finish_reason = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(why) if !text.is_empty() => {
+                eprintln!("Stream broke off, keeping the partial reply: {}", &why);
+                break;
+            }
+            Err(why) => return Err(why),
+        };
+        text.push_str(&chunk.text);
+        if chunk.finish_reason.is_some() {
+            finish_reason = chunk.finish_reason;
         }
-        .default_configuration()
+        // nobody watching anymore isn't a reason to stop generating
+        let _ = progress.send(format!("{}{}", prefix, text));
     }
-    fn prepare(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
-        match self {
-            TransformerKind::Conversation(trans) => trans,
+    Ok((text, finish_reason))
+}
+
+/// Edits the newest text from `updates` into `message_id`, at most once per
+/// [`STREAM_EDIT_INTERVAL`]. Whatever is left over goes up as soon as `updates` closes. Returns
+/// what the message was last edited to
+async fn edit_progressively(
+    platform: &dyn ChatPlatform,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    mut updates: mpsc::UnboundedReceiver<String>,
+) -> Option<String> {
+    let mut shown = None;
+    let mut pending: Option<String> = None;
+    // sending the placeholder counts against the same limit
+    let mut next_edit = time::Instant::now() + STREAM_EDIT_INTERVAL;
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Some(text) => pending = Some(text),
+                None => break,
+            },
+            _ = time::delay_until(next_edit), if pending.is_some() => {
+                if let Some(text) = pending.take() {
+                    show_progress(platform, channel_id, message_id, &text, &mut shown).await;
+                }
+                next_edit = time::Instant::now() + STREAM_EDIT_INTERVAL;
+            }
         }
-        .prepare(buf)
     }
-    fn transform(&self, buf: impl std::fmt::Write, log_item: &LogItem) -> std::fmt::Result {
-        match self {
-            TransformerKind::Conversation(trans) => trans,
-        }
-        .transform(buf, log_item)
+    if let Some(text) = pending {
+        show_progress(platform, channel_id, message_id, &text, &mut shown).await;
     }
-    fn append_prompt(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
-        match self {
-            TransformerKind::Conversation(trans) => trans,
-        }
-        .append_prompt(buf)
+    shown
+}
+
+async fn show_progress(
+    platform: &dyn ChatPlatform,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    text: &str,
+    shown: &mut Option<String>,
+) {
+    let message = MessageBuilder::new().push_safe(text.trim()).build();
+    // Discord refuses empty messages
+    if message.is_empty() || shown.as_ref() == Some(&message) {
+        return;
     }
-    fn get_stop_params(&self) -> Option<Vec<String>> {
-        match self {
-            TransformerKind::Conversation(trans) => trans,
-        }
-        .stop_tokens()
+    match platform
+        .edit_message(channel_id, message_id, &message)
+        .await
+    {
+        Ok(()) => *shown = Some(message),
+        Err(why) => eprintln!("Failed to edit streamed reply: {}", &why),
     }
 }
 
-fn get_engine_url(engine: &str) -> String {
-    format!("https://api.openai.com/v1/engines/{}/completions", engine)
+async fn keep_typing(platform: &dyn ChatPlatform, channel_id: ChannelId) {
+    loop {
+        time::delay_for(TYPING_INTERVAL).await;
+        if let Err(why) = platform.broadcast_typing(channel_id).await {
+            eprintln!("Failed to broadcast typing: {:?}", &why);
+        }
+    }
 }
 
-async fn create_completion(
-    api_key: &str,
-    params: CompletionParameters,
-) -> Result<CompletionResponse, surf::Error> {
-    let url = get_engine_url(&*params.engine);
-    let req = surf::post(url).header("Authorization", format!("Bearer {}", api_key));
-    let body = req
-        .body(surf::Body::from_json(&params)?)
-        .recv_string()
-        .await?;
-    // println!("{}", &body);
-    eprintln!("Read {} long response", body.len());
-    Ok(serde_json::from_str(&body).expect("omg"))
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engines::{
+            mock_server::{MockResponse, MockServer},
+            MessageSessionHandler,
+        },
+        error::Error,
+        jobs::settle,
+        openai::{OpenAI, StreamChunk},
+        platform::{Event, InMemory},
+    };
 
-#[derive(Default, Debug, Clone, serde::Serialize)]
-pub struct CompletionParameters {
-    #[serde(skip)]
-    pub engine: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub prompt: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub max_tokens: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temperature: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub top_p: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub n: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub logprobs: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub echo: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub stop: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub presence_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub frequency_penalty: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub best_of: Option<usize>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum FinishReason {
-    Length,
-    Stop,
-}
-
-#[derive(Debug, serde::Deserialize)]
-#[serde(untagged)]
-enum CompletionResponse {
-    Success {
-        id: String,
-        object: Option<serde_json::Value>,
-
-        // TODO(haze): replace with chrono time
-        created: usize,
-        model: String,
-        choices: Vec<Choice>,
-    },
-    Error {
-        error: CompletionError,
-    },
-}
+    /// Stands in for the API in tests that never get as far as a request
+    struct Offline;
 
-#[derive(Debug, serde::Deserialize)]
-struct CompletionError {
-    code: Option<usize>,
-    message: String,
-    #[serde(rename = "type")]
-    kind: String,
-}
-
-#[derive(Debug, serde::Deserialize)]
-struct Choice {
-    text: String,
-    index: usize,
-    logprobs: Option<LogProbs>,
-    finish_reason: Option<FinishReason>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-struct LogProbs {
-    tokens: Vec<String>,
-}
-
-async fn count_tokens(
-    api_key: &str,
-    text: String,
-    params: CompletionParameters,
-) -> Result<Option<usize>, surf::Error> {
-    let response = create_completion(
-        api_key,
-        CompletionParameters {
-            prompt: Some(text),
-            logprobs: Some(10),
-            max_tokens: Some(0),
-            echo: Some(true),
-            ..params
-        },
-    )
-    .await?;
-    if let CompletionResponse::Success { choices, .. } = response {
-        if let Some(first_choice_logprobs) =
-            choices.first().and_then(|choice| choice.logprobs.as_ref())
-        {
-            return Ok(Some(first_choice_logprobs.tokens.len()));
+    #[serenity::async_trait]
+    impl CompletionProvider for Offline {
+        async fn create_completion(
+            &self,
+            _params: &CompletionParameters,
+        ) -> crate::error::Result<Completion> {
+            Err(Error::Surf(String::from("Tests don't have network access")))
         }
     }
-    Ok(None)
-}
 
-pub async fn log_transformer_from_serenity_args(
-    context: &serenity::prelude::Context,
-    args: &mut serenity::framework::standard::Args,
-) -> Result<(TransformerKind, String), crate::commands::StringError> {
-    // 0th, the engine
-    let engine = {
-        if let Ok(arg) = args.single::<String>() {
-            match &*arg.to_lowercase() {
-                "default" => String::from("davinci"),
-                _ => arg,
-            }
-        } else {
-            return Err("Missing engine (if you aren't sure, use `default`)".into());
+    fn test_handler() -> GPT3MessageHandler {
+        GPT3MessageHandler::new(
+            TransformerKind::Conversation(conversation::Transformer {
+                ai_name: String::from("Ai"),
+                context: Some(String::from("context here!")),
+                summary: None,
+            }),
+            Arc::new(Offline),
+        )
+    }
+
+    fn mock_handler(server: &MockServer) -> GPT3MessageHandler {
+        let mut session = test_handler();
+        session.provider = Arc::new(OpenAI::new(String::from("test-key"), server.base_url()));
+        session.set_engine(String::from("davinci"));
+        session.retry_policy = RetryPolicy {
+            max_retries: 2,
+            base_delay_ms: 1,
+            max_delay_ms: 5,
+        };
+        session
+    }
+
+    fn mock_payload() -> Payload {
+        Payload {
+            channel_id: ChannelId(1),
+            tokenizer: Arc::new(crate::tokenizer::test_tokenizer()),
+            local_summarizer: Arc::new(LocalSummarizer::default()),
         }
-    };
-    // first, the transform type
-    let transform_type = args.single::<String>()?.to_lowercase();
-    Ok(match &*transform_type {
-        // if we get single, then get the ai name (otherwise, default to the bots name)
-        "conversation" | "convo" => {
-            let bot_name = context.http.get_current_application_info().await?.name;
-            let ai_name = {
-                let temp = args
-                    .single_quoted()
-                    // it's ok to do this since I don't expet this method to be called frequently
-                    .unwrap_or_else(|_| bot_name.clone());
-                if temp == "_" {
-                    bot_name
-                } else {
-                    temp
-                }
-            };
-            let context = {
-                let rest = args.rest();
-                let trimmed = rest.trim().trim_matches('`').trim_matches('"').trim();
-                if trimmed.is_empty() {
-                    None
-                } else {
-                    Some(trimmed.to_string())
-                }
-            }
-            .map(|context| context.replace("{name}", &*ai_name));
-            dbg!(&context);
-            (
-                TransformerKind::Conversation(conversation::Transformer { ai_name, context }),
-                engine,
+    }
+
+    #[test]
+    fn session_conversation_to_string() {
+        let tokenizer = crate::tokenizer::test_tokenizer();
+        let mut session = test_handler();
+        session
+            .record(
+                LogItem {
+                    author_name: Some(String::from("foo")),
+                    author_nick: Some(String::from("foo-nick")),
+                    text: String::from("bar"),
+                    sent_by_ai: false,
+                    message_id: None,
+                },
+                &tokenizer,
+            )
+            .expect("Recording a line should not fail");
+        session
+            .record(
+                LogItem {
+                    author_name: Some(String::from("fredi")),
+                    author_nick: Some(String::from("foo-nick")),
+                    text: String::from("hello, world"),
+                    sent_by_ai: false,
+                    message_id: None,
+                },
+                &tokenizer,
             )
+            .expect("Recording a line should not fail");
+        session
+            .record(
+                LogItem {
+                    author_name: None,
+                    author_nick: None,
+                    text: String::from("hello, human"),
+                    sent_by_ai: true,
+                    message_id: None,
+                },
+                &tokenizer,
+            )
+            .expect("Recording a line should not fail");
+        assert_eq!(
+            session
+                .make_string()
+                .expect("Session should be able to be converted into a string"),
+            "context here!\n\nUser (foo-nick): bar\nUser (foo-nick): hello, world\nAi: hello, human\n"
+        );
+        assert_eq!(
+            session.token_count,
+            tokenizer.count(&session.make_string().unwrap())
+        );
+    }
+
+    #[test]
+    fn ensure_is_safe_trims_oldest_lines_first() {
+        let tokenizer = crate::tokenizer::test_tokenizer();
+        let mut session = test_handler();
+        session.budget = TokenBudget {
+            max_prompt_tokens: Some(80),
+            reserved_completion_tokens: 16,
+            keep_last_turns: 2,
+            summarizer: Summarizer::Engine,
+        };
+        for index in 0..10 {
+            session
+                .record(
+                    LogItem {
+                        author_name: Some(String::from("foo")),
+                        author_nick: None,
+                        text: format!("line {}", index),
+                        sent_by_ai: false,
+                        message_id: None,
+                    },
+                    &tokenizer,
+                )
+                .expect("Recording a line should not fail");
         }
-        _ => return Err("Invalid conversation type".into()),
-    })
-}
+        session
+            .ensure_is_safe(&tokenizer)
+            .expect("Trimming should not fail");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let prompt = session.make_prompt(None).unwrap();
+        assert!(tokenizer.count(&prompt) <= 80);
+        assert!(prompt.starts_with("context here!"));
+        assert_eq!(session.message_log.last().unwrap().text, "line 9");
+        assert_eq!(
+            session.unsummarized.len() + session.message_log.len(),
+            10,
+            "Trimmed lines should be queued for summarization"
+        );
+
+        // the last turns survive even when the budget can't be met
+        session.budget.max_prompt_tokens = Some(1);
+        session
+            .ensure_is_safe(&tokenizer)
+            .expect("Trimming should not fail");
+        assert_eq!(session.message_log.len(), 2);
+        assert_eq!(session.message_log[0].text, "line 8");
+    }
 
     #[test]
-    fn session_conversation_to_string() {
-        let token = "test_token";
-        let mut session =
-            GPT3MessageHandler::new(TransformerKind::Conversation(conversation::Transformer {
-                ai_name: String::from("Ai"),
-                context: Some(String::from("context here!")),
-            }));
-        session.record(
-            LogItem {
-                author_name: Some(String::from("foo")),
-                author_nick: Some(String::from("foo-nick")),
-                text: String::from("bar"),
-                sent_by_ai: false,
-            },
-            &token,
+    fn lines_waiting_on_a_summary_are_capped() {
+        let tokenizer = crate::tokenizer::test_tokenizer();
+        let mut session = test_handler();
+        session.budget = TokenBudget {
+            max_prompt_tokens: Some(1),
+            reserved_completion_tokens: 16,
+            keep_last_turns: 0,
+            summarizer: Summarizer::Local,
+        };
+        for index in 0..MAX_UNSUMMARIZED_LINES + 5 {
+            session
+                .record(
+                    LogItem {
+                        author_name: Some(String::from("foo")),
+                        author_nick: None,
+                        text: format!("line {}", index),
+                        sent_by_ai: false,
+                        message_id: None,
+                    },
+                    &tokenizer,
+                )
+                .expect("Recording a line should not fail");
+        }
+        session
+            .ensure_is_safe(&tokenizer)
+            .expect("Trimming should not fail");
+        assert_eq!(session.unsummarized.len(), MAX_UNSUMMARIZED_LINES);
+        assert_eq!(session.unsummarized[0].text, "line 5");
+    }
+
+    #[test]
+    fn retry_delay_is_capped_and_honors_retry_after() {
+        let retry_policy = RetryPolicy {
+            max_retries: 5,
+            base_delay_ms: 100,
+            max_delay_ms: 1_000,
+        };
+        for attempt in 0..40 {
+            assert!(retry_policy.delay(attempt, None) <= Duration::from_millis(1_000));
+        }
+        assert!(retry_policy.delay(0, None) <= Duration::from_millis(100));
+        assert_eq!(
+            retry_policy.delay(0, Some(Duration::from_millis(800))),
+            Duration::from_millis(800)
         );
-        session.record(
-            LogItem {
-                author_name: Some(String::from("fredi")),
-                author_nick: Some(String::from("foo-nick")),
-                text: String::from("hello, world"),
-                sent_by_ai: false,
-            },
-            &token,
+        assert_eq!(
+            retry_policy.delay(0, Some(Duration::from_secs(3_600))),
+            Duration::from_millis(1_000),
+            "A Retry-After past the cap is cut short"
         );
-        session.record(
-            LogItem {
-                author_name: None,
-                author_nick: None,
-                text: String::from("hello, human"),
-                sent_by_ai: true,
-            },
-            &token,
+    }
+
+    #[tokio::test]
+    async fn get_response_continues_cut_off_completions() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("hello", "length"),
+            MockResponse::completion(" there", "stop"),
+        ]);
+        let session = mock_handler(&server);
+        let response = session
+            .get_response(
+                session.configuration.clone(),
+                &crate::tokenizer::test_tokenizer(),
+                None,
+            )
+            .await
+            .expect("Completion should succeed");
+        assert_eq!(response.as_deref(), Some("hello there"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/v1/engines/davinci/completions");
+        assert_eq!(
+            requests[0].authorization.as_deref(),
+            Some("Bearer test-key")
         );
+        assert!(requests[0].prompt().ends_with("Ai: "));
+        assert!(requests[1].prompt().ends_with("Ai: hello"));
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let server = MockServer::start(vec![
+            MockResponse::error(503, "overloaded"),
+            MockResponse::error(429, "slow down").header("Retry-After", "0"),
+            MockResponse::completion("made it", "stop"),
+        ]);
+        let session = mock_handler(&server);
+        let response = session
+            .get_response(
+                session.configuration.clone(),
+                &crate::tokenizer::test_tokenizer(),
+                None,
+            )
+            .await
+            .expect("Completion should succeed after retrying");
+        assert_eq!(response.as_deref(), Some("made it"));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let server = MockServer::start(vec![
+            MockResponse::error(401, "bad key"),
+            MockResponse::completion("never sent", "stop"),
+        ]);
+        let session = mock_handler(&server);
+        match session
+            .get_response(
+                session.configuration.clone(),
+                &crate::tokenizer::test_tokenizer(),
+                None,
+            )
+            .await
+        {
+            Err(Error::Unauthorized(message)) => assert_eq!(message, "bad key"),
+            other => panic!("Expected an auth error, got {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_reply_summarizes_trimmed_lines_and_records_the_reply() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("Foo counted to seven.", "stop"),
+            MockResponse::completion(" eight!", "stop"),
+            MockResponse::completion("Foo counted to eight.", "stop"),
+        ]);
+        let payload = mock_payload();
+        let mut session = mock_handler(&server);
+        // past the script the mock only returns errors, so don't wait around retrying them
+        session.retry_policy.max_retries = 0;
+        session.budget = TokenBudget {
+            max_prompt_tokens: Some(80),
+            reserved_completion_tokens: 16,
+            keep_last_turns: 2,
+            summarizer: Summarizer::Engine,
+        };
+        for index in 0..10 {
+            session
+                .record(
+                    LogItem {
+                        author_name: Some(String::from("foo")),
+                        author_nick: None,
+                        text: format!("line {}", index),
+                        sent_by_ai: false,
+                        message_id: None,
+                    },
+                    &payload.tokenizer,
+                )
+                .expect("Recording a line should not fail");
+        }
+
+        let reply = session
+            .generate_reply(&payload)
+            .await
+            .expect("Generating a reply should succeed");
+        assert_eq!(reply.as_deref(), Some("eight!"));
+        let last = session.message_log.last().unwrap();
+        assert!(last.sent_by_ai);
+        assert_eq!(last.text, "eight!");
+        assert!(session.transformer.get_summary().is_some());
+
+        let requests = server.requests();
+        assert!(requests[0]
+            .prompt()
+            .contains("Summary of the conversation so far"));
+        assert!(requests[1]
+            .prompt()
+            .contains("Earlier in the conversation: Foo counted to seven."));
         assert_eq!(
+            requests[1].body["max_tokens"],
+            serde_json::json!(16),
+            "The reserved completion tokens should be requested"
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_from_a_snapshot_keep_lines_recorded_meanwhile() {
+        let server = MockServer::start(vec![
+            MockResponse::completion("Foo counted to seven.", "stop"),
+            MockResponse::completion(" eight!", "stop"),
+            MockResponse::completion("Foo counted to eight.", "stop"),
+        ]);
+        let payload = mock_payload();
+        let mut session = mock_handler(&server);
+        session.retry_policy.max_retries = 0;
+        session.budget = TokenBudget {
+            max_prompt_tokens: Some(80),
+            reserved_completion_tokens: 16,
+            keep_last_turns: 2,
+            summarizer: Summarizer::Engine,
+        };
+        let line = |text: &str| LogItem {
+            author_name: Some(String::from("foo")),
+            author_nick: None,
+            text: text.to_string(),
+            sent_by_ai: false,
+            message_id: None,
+        };
+        for index in 0..10 {
             session
-                .to_string()
-                .expect("Session should be able to be converted into a string")
-                .len(),
-            61
+                .record(line(&format!("line {}", index)), &payload.tokenizer)
+                .expect("Recording a line should not fail");
+        }
+
+        let mut snapshot = session.snapshot();
+        let reply = snapshot
+            .handler
+            .generate_reply(&payload)
+            .await
+            .expect("Generating a reply should succeed");
+        session
+            .record(line("meanwhile"), &payload.tokenizer)
+            .expect("Recording a line should not fail");
+        let summary = snapshot.handler.transformer.get_summary().clone();
+        session.commit(
+            snapshot,
+            reply.map(super::super::reply_line),
+            &payload.tokenizer,
+        );
+
+        assert!(summary.is_some());
+        assert_eq!(*session.transformer.get_summary(), summary);
+        let texts: Vec<_> = session
+            .message_log
+            .iter()
+            .map(|log_item| &*log_item.text)
+            .collect();
+        assert_eq!(texts[texts.len() - 2..], ["meanwhile", "eight!"]);
+        assert!(
+            !texts.contains(&"line 0"),
+            "Lines the snapshot summarized shouldn't come back"
+        );
+        assert!(session.take_usage().requests > 0);
+    }
+
+    #[tokio::test]
+    async fn chat_mode_sends_role_messages() {
+        let server = MockServer::start(vec![MockResponse::chat_completion("hello, foo")]);
+        let payload = mock_payload();
+        let mut session = mock_handler(&server);
+        session.request_mode = RequestMode::Chat;
+        session.set_engine(String::from("gpt-3.5-turbo"));
+        session
+            .record(
+                LogItem {
+                    author_name: Some(String::from("foo")),
+                    author_nick: None,
+                    text: String::from("hi there"),
+                    sent_by_ai: false,
+                    message_id: None,
+                },
+                &payload.tokenizer,
+            )
+            .expect("Recording a line should not fail");
+
+        let reply = session
+            .generate_reply(&payload)
+            .await
+            .expect("Generating a reply should succeed");
+        assert_eq!(reply.as_deref(), Some("hello, foo"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].body["model"], "gpt-3.5-turbo");
+        let messages = requests[0].body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "system");
+        assert!(messages[0]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("context here!"));
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"], "foo: hi there");
+        assert!(
+            requests[0]
+                .body
+                .get("stop")
+                .map_or(true, |stop| stop.is_null()),
+            "Chat replies aren't cut at newlines or the AI's name"
+        );
+    }
+
+    #[test]
+    fn dated_engines_get_their_family_context_size() {
+        assert_eq!(context_size("gpt-4-0613"), 8_192);
+        assert_eq!(context_size("gpt-4-32k-0613"), 32_768);
+        assert_eq!(context_size("gpt-3.5-turbo-16k"), 16_384);
+        assert_eq!(context_size("gpt-3.5-turbo-0301"), 4_096);
+        assert_eq!(context_size("davinci"), GPT_MAX_TOKEN_LEN);
+    }
+
+    #[test]
+    fn engines_have_to_fit_the_request_mode() {
+        let mut session = test_handler();
+        assert!(session
+            .tune(Tuning::Engine(String::from("gpt-3.5-turbo")))
+            .is_err());
+        session
+            .tune(Tuning::RequestMode(RequestMode::Chat))
+            .expect("Any session can switch modes");
+        assert_eq!(session.configuration.engine, DEFAULT_CHAT_ENGINE);
+        assert!(session
+            .tune(Tuning::Engine(String::from("davinci")))
+            .is_err());
+        session
+            .tune(Tuning::Engine(String::from("gpt-4")))
+            .expect("gpt-4 is a chat engine");
+    }
+
+    #[test]
+    fn tuning_stays_within_the_rules() {
+        let mut session = test_handler();
+        let mut rules = crate::config::EngineRules {
+            engines: vec![String::from("gpt3")],
+            max_tokens: Some(100),
+            ..Default::default()
+        };
+        assert!(session
+            .check_limits(&Tuning::MaxTokens(200), &rules)
+            .is_err());
+        assert!(session
+            .check_limits(&Tuning::MaxTokens(100), &rules)
+            .is_ok());
+        let chat_mode = Tuning::RequestMode(RequestMode::Chat);
+        assert!(session.check_limits(&chat_mode, &rules).is_err());
+
+        rules.engines.push(String::from("gpt-3.5-turbo"));
+        assert!(session.check_limits(&chat_mode, &rules).is_ok());
+        session
+            .tune(chat_mode)
+            .expect("Any session can switch modes");
+        assert!(session
+            .check_limits(&Tuning::Engine(String::from("gpt-4")), &rules)
+            .is_err());
+
+        // denying the engine later puts the session back on a text engine
+        rules.engines.pop();
+        session.apply_limits(&rules);
+        assert_eq!(session.request_mode, RequestMode::Text);
+        assert_eq!(session.configuration.engine, DEFAULT_ENGINE);
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_edit_the_placeholder() {
+        let server = MockServer::start(vec![MockResponse::stream(&[" hello", ",", " @everyone"])]);
+        let platform = Arc::new(InMemory::default());
+        let mut session = mock_handler(&server);
+        session.stream_replies = true;
+
+        session
+            .perform_work(
+                &(Arc::clone(&platform) as Arc<dyn ChatPlatform>),
+                mock_payload(),
+            )
+            .await;
+
+        let reply = MessageBuilder::new().push_safe("hello, @everyone").build();
+        assert_eq!(
+            platform.events(),
+            vec![
+                Event::Message {
+                    channel_id: ChannelId(1),
+                    text: String::from(STREAM_PLACEHOLDER),
+                },
+                Event::Edit {
+                    message_id: MessageId(1),
+                    text: reply,
+                },
+            ]
         );
+        assert_eq!(server.requests()[0].body["stream"], serde_json::json!(true));
+        assert_eq!(
+            session.message_log.last().map(|line| &*line.text),
+            Some("hello, @everyone")
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_streams_keep_what_came_through() {
+        let (progress, _updates) = mpsc::unbounded_channel();
+        let chunk = |text: &str| {
+            Ok(StreamChunk {
+                text: text.to_string(),
+                finish_reason: None,
+            })
+        };
+        let stream = futures::stream::iter(vec![
+            chunk(" hello"),
+            chunk(" there"),
+            Err(Error::Surf(String::from("connection reset"))),
+        ])
+        .boxed();
+        let (text, finish_reason) = collect_stream(stream, "", &progress)
+            .await
+            .expect("The partial reply is kept");
+        assert_eq!(text, " hello there");
+        assert_eq!(finish_reason, None);
+
+        let stream = futures::stream::iter(vec![Err(Error::Surf(String::from("reset")))]).boxed();
+        assert!(collect_stream(stream, "", &progress).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streamed_edits_are_throttled() {
+        time::pause();
+        let platform = Arc::new(InMemory::default());
+        let (progress, updates) = mpsc::unbounded_channel();
+        let editor = {
+            let platform = Arc::clone(&platform);
+            tokio::spawn(async move {
+                edit_progressively(&*platform, ChannelId(1), MessageId(1), updates).await
+            })
+        };
+        progress.send(String::from(" Hel")).unwrap();
+        progress.send(String::from(" Hello")).unwrap();
+        settle().await;
+        assert!(
+            platform.events().is_empty(),
+            "The first edit waits out the interval"
+        );
+
+        time::advance(STREAM_EDIT_INTERVAL).await;
+        settle().await;
+        progress.send(String::from(" Hello th")).unwrap();
+        settle().await;
+        progress.send(String::from(" Hello there")).unwrap();
+        drop(progress);
+        let shown = editor.await.expect("Editor panicked");
+
+        let edits = platform
+            .events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Edit { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(edits, vec!["Hello", "Hello there"]);
+        assert_eq!(shown.as_deref(), Some("Hello there"));
     }
 }
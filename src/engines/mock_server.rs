@@ -0,0 +1,244 @@
+/// This file is a stand-in for the completions API, so engine tests can go through real HTTP
+/// without network access. Responses are scripted up front and handed out one per request
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    content_type: &'static str,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl MockResponse {
+    /// A successful completion with a single choice
+    pub fn completion(text: &str, finish_reason: &str) -> MockResponse {
+        let body = serde_json::json!({
+            "id": "cmpl-mock",
+            "object": "text_completion",
+            "created": 0,
+            "model": "mock",
+            "choices": [{
+                "text": text,
+                "index": 0,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }],
+        });
+        MockResponse {
+            status: 200,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// A successful chat completion with a single assistant message
+    pub fn chat_completion(content: &str) -> MockResponse {
+        let body = serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "mock",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": content,
+                },
+                "finish_reason": "stop",
+            }],
+        });
+        MockResponse {
+            status: 200,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// A streamed completion, one server-sent event per chunk. The last chunk finishes with `stop`
+    pub fn stream(chunks: &[&str]) -> MockResponse {
+        let mut body = String::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let finish_reason = if index + 1 == chunks.len() {
+                serde_json::json!("stop")
+            } else {
+                serde_json::Value::Null
+            };
+            let event = serde_json::json!({
+                "id": "cmpl-mock",
+                "object": "text_completion",
+                "created": 0,
+                "model": "mock",
+                "choices": [{
+                    "text": chunk,
+                    "index": 0,
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }],
+            });
+            body.push_str(&format!("data: {}\n\n", event));
+        }
+        body.push_str("data: [DONE]\n\n");
+        MockResponse {
+            status: 200,
+            content_type: "text/event-stream",
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// An error in the shape the API uses
+    pub fn error(status: u16, message: &str) -> MockResponse {
+        let body = serde_json::json!({
+            "error": {
+                "message": message,
+                "type": "mock_error",
+                "code": null,
+            },
+        });
+        MockResponse {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: serde_json::Value,
+}
+
+impl RecordedRequest {
+    pub fn prompt(&self) -> &str {
+        self.body["prompt"].as_str().unwrap_or_default()
+    }
+}
+
+pub struct MockServer {
+    base_url: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    /// Serves `script` in order. Requests past the end of the script get a 500
+    pub fn start(script: Vec<MockResponse>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind mock server");
+        let base_url = format!(
+            "http://{}/v1",
+            listener.local_addr().expect("Mock server has no address")
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        {
+            let requests = Arc::clone(&requests);
+            // the thread outlives the test, it's only ever blocked on accept
+            thread::spawn(move || {
+                let mut script = script.into_iter();
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let response = script.next().unwrap_or_else(|| {
+                        MockResponse::error(500, "The mock server ran out of responses")
+                    });
+                    if let Err(why) = serve(stream, &response, &requests) {
+                        eprintln!("Mock server failed to serve a request: {}", why);
+                    }
+                }
+            });
+        }
+        MockServer { base_url, requests }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests
+            .lock()
+            .expect("Mock server request log poisoned")
+            .clone()
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    response: &MockResponse,
+    requests: &Mutex<Vec<RecordedRequest>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut expects_continue = false;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            let (name, value) = (line[..colon].to_lowercase(), line[colon + 1..].trim());
+            match &*name {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "authorization" => authorization = Some(value.to_string()),
+                "expect" => expects_continue = value.eq_ignore_ascii_case("100-continue"),
+                _ => {}
+            }
+        }
+    }
+
+    let mut writer = stream;
+    if expects_continue {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    requests
+        .lock()
+        .expect("Mock server request log poisoned")
+        .push(RecordedRequest {
+            path,
+            authorization,
+            body: serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        });
+
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(response.body.as_bytes())?;
+    writer.flush()
+}
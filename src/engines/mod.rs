@@ -1,18 +1,200 @@
 pub mod gpt2;
 pub mod gpt3;
-use crate::Session;
+#[cfg(test)]
+pub mod mock_server;
+pub mod openai;
+pub mod summarization;
+use crate::{
+    channel::{Delay, Trigger},
+    commands::StringError,
+    platform::ChatPlatform,
+    transformers::conversation::LogItem,
+    Session,
+};
 use serenity::{
     framework::standard::{Args, CommandError, CommandResult},
-    model::channel::Message,
+    model::{channel::Message, id::MessageId},
     prelude::Context,
 };
+use std::sync::Arc;
 
 #[serenity::async_trait]
 pub trait MessageSessionHandler {
     type Payload;
 
-    async fn perform_work(&mut self, http: &serenity::http::Http, payload: Self::Payload);
+    /// Generates a reply and sends it, returning the line it was recorded as
+    async fn perform_work(
+        &mut self,
+        platform: &Arc<dyn ChatPlatform>,
+        payload: Self::Payload,
+    ) -> Option<LogItem>;
     async fn info(&self, ctx: &Context, msg: &Message, args: Args) -> CommandResult;
     async fn enable(ctx: &Context, msg: &Message, args: Args) -> Result<Session, CommandError>;
     async fn reset(&mut self, ctx: &Context, msg: &Message, args: Args) -> CommandResult;
+    fn tune(&mut self, tuning: Tuning) -> Result<(), StringError>;
+}
+
+/// A single setting changed through the `ConversationTuning` commands. Values are range checked
+/// by the commands, engines only reject settings they don't support
+#[derive(Debug, Clone)]
+pub enum Tuning {
+    Temperature(f64),
+    TopP(f64),
+    PresencePenalty(f64),
+    FrequencyPenalty(f64),
+    MaxTokens(usize),
+    Engine(String),
+    Context(String),
+    MaxRetries(u32),
+    RequestMode(gpt3::RequestMode),
+    /// Whether replies are posted right away and edited as they're generated
+    Streaming(bool),
+    /// How lines trimmed from the prompt are condensed into a summary
+    Summarizer(gpt3::Summarizer),
+    Trigger(Trigger),
+    /// Whether lines that don't trigger a reply are recorded anyway
+    Listen(bool),
+    /// One of the waits before a reply, in milliseconds
+    Delay(Delay, u64),
+}
+
+/// The line a reply is recorded as
+fn reply_line(text: String) -> LogItem {
+    LogItem {
+        author_name: None,
+        author_nick: None,
+        text,
+        sent_by_ai: true,
+        message_id: None,
+    }
+}
+
+/// Remembers which message the reply that was just recorded went out as
+fn tag_last_reply(message_log: &mut [LogItem], message_id: MessageId) {
+    if let Some(reply) = message_log.last_mut() {
+        if reply.sent_by_ai && reply.message_id.is_none() {
+            reply.message_id = Some(message_id);
+        }
+    }
+}
+
+/// Removes the most recent reply. Lines said after it stay in the log
+fn remove_last_reply(message_log: &mut Vec<LogItem>) -> Option<LogItem> {
+    let index = message_log
+        .iter()
+        .rposition(|log_item| log_item.sent_by_ai)?;
+    Some(message_log.remove(index))
+}
+
+/// Removes up to `count` of the most recent lines, returning them oldest first
+fn remove_last_lines(message_log: &mut Vec<LogItem>, count: usize) -> Vec<LogItem> {
+    let start = message_log.len().saturating_sub(count);
+    message_log.split_off(start)
+}
+
+/// Brings the line someone sent as `message_id` up to date after an edit, dropping it if `text`
+/// is `None`. Replies are left alone, the only edits they get are ours. Returns whether there was
+/// such a line
+fn edit_line(message_log: &mut Vec<LogItem>, message_id: MessageId, text: Option<&str>) -> bool {
+    let index = match message_log
+        .iter()
+        .position(|log_item| !log_item.sent_by_ai && log_item.message_id == Some(message_id))
+    {
+        Some(index) => index,
+        None => return false,
+    };
+    match text {
+        Some(text) => message_log[index].text = text.to_string(),
+        None => {
+            message_log.remove(index);
+        }
+    }
+    true
+}
+
+/// Removes the lines that came from or went out as any of `message_ids`, returning how many
+fn remove_lines(message_log: &mut Vec<LogItem>, message_ids: &[MessageId]) -> usize {
+    let before = message_log.len();
+    message_log.retain(|log_item| {
+        log_item
+            .message_id
+            .map_or(true, |message_id| !message_ids.contains(&message_id))
+    });
+    before - message_log.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_item(text: &str, sent_by_ai: bool) -> LogItem {
+        LogItem {
+            author_name: None,
+            author_nick: None,
+            text: text.to_string(),
+            sent_by_ai,
+            message_id: None,
+        }
+    }
+
+    #[test]
+    fn last_reply_is_removed_and_later_lines_are_kept() {
+        let mut message_log = vec![
+            log_item("hello", false),
+            log_item("hi!", true),
+            log_item("how are you?", false),
+        ];
+        tag_last_reply(&mut message_log, MessageId(7));
+        assert_eq!(
+            message_log[1].message_id, None,
+            "Only a trailing reply is tagged"
+        );
+
+        let reply = remove_last_reply(&mut message_log).expect("There is a reply");
+        assert_eq!(reply.text, "hi!");
+        assert_eq!(
+            message_log
+                .iter()
+                .map(|log_item| &*log_item.text)
+                .collect::<Vec<_>>(),
+            vec!["hello", "how are you?"]
+        );
+        assert!(remove_last_reply(&mut message_log).is_none());
+
+        let forgotten = remove_last_lines(&mut message_log, 5);
+        assert_eq!(forgotten.len(), 2);
+        assert!(message_log.is_empty());
+    }
+
+    #[test]
+    fn edits_and_deletions_follow_message_ids() {
+        let mut message_log = vec![
+            LogItem {
+                message_id: Some(MessageId(1)),
+                ..log_item("helo", false)
+            },
+            LogItem {
+                message_id: Some(MessageId(2)),
+                ..log_item("hi!", true)
+            },
+            log_item("untracked", false),
+        ];
+        assert!(edit_line(&mut message_log, MessageId(1), Some("hello")));
+        assert_eq!(message_log[0].text, "hello");
+        assert!(!edit_line(&mut message_log, MessageId(2), Some("hijacked")));
+        assert!(!edit_line(&mut message_log, MessageId(2), None));
+        assert_eq!(message_log[1].text, "hi!");
+
+        assert_eq!(
+            remove_lines(&mut message_log, &[MessageId(2), MessageId(3)]),
+            1
+        );
+        assert_eq!(
+            message_log
+                .iter()
+                .map(|log_item| &*log_item.text)
+                .collect::<Vec<_>>(),
+            vec!["hello", "untracked"]
+        );
+    }
 }
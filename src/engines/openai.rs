@@ -0,0 +1,521 @@
+/// This file talks to the completions API. Anything that speaks the OpenAI wire format (OpenAI
+/// itself, a compatible local server, a test double) can stand behind [`CompletionProvider`]
+use crate::error::{Error, Result};
+use futures::{
+    future,
+    io::AsyncBufReadExt,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// The pieces of a streamed completion, in the order they arrive
+pub type CompletionStream = BoxStream<'static, Result<StreamChunk>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamChunk {
+    pub text: String,
+    /// Only set on the last chunk
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[serenity::async_trait]
+pub trait CompletionProvider: Send + Sync {
+    /// Makes a single completion request, without retrying
+    async fn create_completion(&self, params: &CompletionParameters) -> Result<Completion>;
+
+    /// Makes a single chat completion request, without retrying. Not every backend has these
+    async fn create_chat_completion(
+        &self,
+        _params: &ChatCompletionParameters,
+    ) -> Result<ChatCompletion> {
+        Err(Error::InvalidRequest(String::from(
+            "Chat completions aren't supported by this backend",
+        )))
+    }
+
+    /// Like `create_completion`, but hands out the text as it's generated. Backends that can't
+    /// stream send the whole completion as one chunk
+    async fn create_completion_stream(
+        &self,
+        params: &CompletionParameters,
+    ) -> Result<CompletionStream> {
+        let completion = self.create_completion(params).await?;
+        let chunks = completion.choices.into_iter().take(1).map(|choice| {
+            Ok(StreamChunk {
+                text: choice.text,
+                finish_reason: choice.finish_reason,
+            })
+        });
+        Ok(stream::iter(chunks.collect::<Vec<_>>()).boxed())
+    }
+
+    /// Like `create_chat_completion`, but hands out the message as it's generated
+    async fn create_chat_completion_stream(
+        &self,
+        params: &ChatCompletionParameters,
+    ) -> Result<CompletionStream> {
+        let completion = self.create_chat_completion(params).await?;
+        let chunks = completion.choices.into_iter().take(1).map(|choice| {
+            Ok(StreamChunk {
+                text: choice.message.content,
+                finish_reason: choice.finish_reason,
+            })
+        });
+        Ok(stream::iter(chunks.collect::<Vec<_>>()).boxed())
+    }
+}
+
+pub struct OpenAI {
+    api_key: String,
+    base_url: String,
+}
+
+impl OpenAI {
+    /// `base_url` is everything before `/engines/...`, e.g. [`DEFAULT_BASE_URL`]
+    pub fn new(api_key: String, base_url: impl Into<String>) -> OpenAI {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        OpenAI { api_key, base_url }
+    }
+
+    fn completions_url(&self, engine: &str) -> String {
+        format!("{}/engines/{}/completions", self.base_url, engine)
+    }
+
+    fn chat_completions_url(&self) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    async fn send(
+        &self,
+        url: String,
+        params: &(impl serde::Serialize + Sync),
+    ) -> Result<surf::Response> {
+        // failing to serialize our own parameters is a bug, not worth retrying
+        let mut body = surf::Body::from_bytes(serde_json::to_vec(params)?);
+        body.set_mime(surf::http::mime::JSON);
+        surf::post(url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .body(body)
+            .await
+            .map_err(|why| Error::Surf(why.to_string()))
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        url: String,
+        params: &(impl serde::Serialize + Sync),
+    ) -> Result<T> {
+        let mut response = self.send(url, params).await?;
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        let body = response
+            .body_string()
+            .await
+            .map_err(|why| Error::Surf(why.to_string()))?;
+        eprintln!("Read {} long response", body.len());
+        parse_completion_response(status.into(), retry_after, body)
+    }
+
+    /// Posts a request with `"stream": true` set and reads the server-sent events as they come
+    async fn stream(
+        &self,
+        url: String,
+        params: &(impl serde::Serialize + Sync),
+    ) -> Result<CompletionStream> {
+        let mut response = self.send(url, params).await?;
+        let status = u16::from(response.status());
+        if !(200..300).contains(&status) {
+            let retry_after = retry_after(&response);
+            let body = response
+                .body_string()
+                .await
+                .map_err(|why| Error::Surf(why.to_string()))?;
+            return Err(error_from_response(status, retry_after, body));
+        }
+        let chunks = futures::io::BufReader::new(response)
+            .lines()
+            .map_err(Error::from)
+            .try_filter_map(|line| future::ready(parse_stream_line(&line)));
+        Ok(chunks.boxed())
+    }
+}
+
+fn retry_after(response: &surf::Response) -> Option<Duration> {
+    response
+        .header("Retry-After")
+        .and_then(|values| values.last().as_str().trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+#[serenity::async_trait]
+impl CompletionProvider for OpenAI {
+    async fn create_completion(&self, params: &CompletionParameters) -> Result<Completion> {
+        self.post(self.completions_url(&*params.engine), params)
+            .await
+    }
+
+    async fn create_chat_completion(
+        &self,
+        params: &ChatCompletionParameters,
+    ) -> Result<ChatCompletion> {
+        self.post(self.chat_completions_url(), params).await
+    }
+
+    async fn create_completion_stream(
+        &self,
+        params: &CompletionParameters,
+    ) -> Result<CompletionStream> {
+        let params = CompletionParameters {
+            stream: Some(true),
+            ..params.clone()
+        };
+        self.stream(self.completions_url(&*params.engine), &params)
+            .await
+    }
+
+    async fn create_chat_completion_stream(
+        &self,
+        params: &ChatCompletionParameters,
+    ) -> Result<CompletionStream> {
+        let params = ChatCompletionParameters {
+            stream: Some(true),
+            ..params.clone()
+        };
+        self.stream(self.chat_completions_url(), &params).await
+    }
+}
+
+/// Sorts a completions response into a `T` or the matching [`Error`], keeping the raw body around
+/// whenever it isn't what we expected
+fn parse_completion_response<T: DeserializeOwned>(
+    status: u16,
+    retry_after: Option<Duration>,
+    body: String,
+) -> Result<T> {
+    if (200..300).contains(&status) {
+        match serde_json::from_str::<T>(&body) {
+            Ok(completion) => return Ok(completion),
+            // errors don't always come with an error status
+            Err(_) if serde_json::from_str::<ErrorResponse>(&body).is_ok() => {}
+            Err(source) => return Err(Error::Decode { source, body }),
+        }
+    }
+    Err(error_from_response(status, retry_after, body))
+}
+
+/// Picks the [`Error`] for a response that didn't carry a completion
+fn error_from_response(status: u16, retry_after: Option<Duration>, body: String) -> Error {
+    let parsed = serde_json::from_str::<ErrorResponse>(&body).ok();
+    let is_parsed = parsed.is_some();
+    let message = match parsed {
+        Some(ErrorResponse { error }) => error.message,
+        // error pages from proxies in front of the API aren't JSON
+        None => body,
+    };
+    match status {
+        401 | 403 => Error::Unauthorized(message),
+        429 => Error::RateLimited {
+            message,
+            retry_after,
+        },
+        // the message gets shown in chat, so it has to be the API's own
+        400 | 404 | 422 if is_parsed => Error::InvalidRequest(message),
+        _ => Error::Http {
+            status,
+            body: message,
+        },
+    }
+}
+
+/// Reads one line of a server-sent event stream. Only `data:` lines carry chunks, and the closing
+/// `data: [DONE]` carries nothing
+fn parse_stream_line(line: &str) -> Result<Option<StreamChunk>> {
+    let data = match line.strip_prefix("data:") {
+        Some(data) => data.trim(),
+        None => return Ok(None),
+    };
+    if data.is_empty() || data == "[DONE]" {
+        return Ok(None);
+    }
+    let event: StreamEvent = serde_json::from_str(data).map_err(|source| Error::Decode {
+        source,
+        body: data.to_string(),
+    })?;
+    Ok(event.choices.into_iter().next().map(|choice| StreamChunk {
+        text: choice
+            .text
+            .or_else(|| choice.delta.and_then(|delta| delta.content))
+            .unwrap_or_default(),
+        finish_reason: choice.finish_reason,
+    }))
+}
+
+#[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CompletionParameters {
+    #[serde(skip)]
+    pub engine: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+/// Request body for `/chat/completions`
+#[derive(Default, Debug, Clone, serde::Serialize)]
+pub struct ChatCompletionParameters {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Length,
+    Stop,
+    ContentFilter,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorResponse {
+    error: CompletionError,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Completion {
+    pub id: String,
+    pub object: Option<serde_json::Value>,
+
+    // TODO(haze): replace with chrono time
+    pub created: usize,
+    pub model: String,
+    pub choices: Vec<Choice>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CompletionError {
+    code: Option<serde_json::Value>,
+    message: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Choice {
+    pub text: String,
+    pub index: usize,
+    pub logprobs: Option<LogProbs>,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct LogProbs {
+    pub tokens: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub object: Option<serde_json::Value>,
+    pub created: usize,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChatChoice {
+    pub index: usize,
+    pub message: ChatMessage,
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// One `data:` event of a stream. Text completions carry `text`, chat completions a `delta`
+#[derive(Debug, serde::Deserialize)]
+struct StreamEvent {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    delta: Option<StreamDelta>,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StreamDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_url_joins_base_url() {
+        let provider = OpenAI::new(String::from("key"), "http://localhost:8080/v1/");
+        assert_eq!(
+            provider.completions_url("davinci"),
+            "http://localhost:8080/v1/engines/davinci/completions"
+        );
+    }
+
+    #[test]
+    fn chat_completions_parse() {
+        let completion: ChatCompletion = parse_completion_response(
+            200,
+            None,
+            String::from(
+                r#"{"id": "chatcmpl-1", "object": "chat.completion", "created": 0, "model": "gpt-3.5-turbo",
+                    "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}]}"#,
+            ),
+        )
+        .expect("Chat completion should parse");
+        assert_eq!(
+            completion.choices[0].message,
+            ChatMessage::new(Role::Assistant, "hi")
+        );
+    }
+
+    #[test]
+    fn completion_errors_are_classified() {
+        let rate_limited = parse_completion_response::<Completion>(
+            429,
+            Some(Duration::from_secs(3)),
+            String::from(r#"{"error": {"message": "slow down", "type": "requests", "code": null}}"#),
+        );
+        match rate_limited {
+            Err(Error::RateLimited {
+                message,
+                retry_after,
+            }) => {
+                assert_eq!(message, "slow down");
+                assert_eq!(retry_after, Some(Duration::from_secs(3)));
+            }
+            other => panic!("Expected a rate limit, got {:?}", other),
+        }
+        assert!(matches!(
+            parse_completion_response::<Completion>(
+                502,
+                None,
+                String::from("<html>Bad Gateway</html>")
+            ),
+            Err(Error::Http { status: 502, .. })
+        ));
+        assert!(matches!(
+            parse_completion_response::<Completion>(200, None, String::from("not json")),
+            Err(Error::Decode { .. })
+        ));
+        assert!(matches!(
+            parse_completion_response::<Completion>(
+                401,
+                None,
+                String::from(r#"{"error": {"message": "bad key", "type": "invalid_request_error"}}"#)
+            ),
+            Err(Error::Unauthorized(_))
+        ));
+        let proxy_page = parse_completion_response::<Completion>(
+            400,
+            None,
+            String::from("<html>Bad Request</html>"),
+        )
+        .expect_err("A proxy's error page is an error");
+        assert!(matches!(proxy_page, Error::Http { status: 400, .. }));
+        assert!(!proxy_page.user_message().contains("html"));
+    }
+
+    #[test]
+    fn stream_lines_parse() {
+        assert_eq!(
+            parse_stream_line(
+                r#"data: {"choices": [{"text": " hi", "index": 0, "finish_reason": null}]}"#
+            )
+            .expect("Text chunk should parse"),
+            Some(StreamChunk {
+                text: String::from(" hi"),
+                finish_reason: None,
+            })
+        );
+        assert_eq!(
+            parse_stream_line(
+                r#"data: {"choices": [{"delta": {"content": "there"}, "finish_reason": "stop"}]}"#
+            )
+            .expect("Chat chunk should parse"),
+            Some(StreamChunk {
+                text: String::from("there"),
+                finish_reason: Some(FinishReason::Stop),
+            })
+        );
+        assert_eq!(parse_stream_line("data: [DONE]").ok(), Some(None));
+        assert_eq!(parse_stream_line(": keep-alive").ok(), Some(None));
+        assert_eq!(parse_stream_line("").ok(), Some(None));
+        assert!(matches!(
+            parse_stream_line("data: {oops"),
+            Err(Error::Decode { .. })
+        ));
+    }
+}
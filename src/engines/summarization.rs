@@ -0,0 +1,77 @@
+/// This file wraps the local rust-bert summarization pipeline, used to condense chat history that
+/// had to be trimmed without spending completion tokens
+use rust_bert::{
+    pipelines::summarization::{SummarizationConfig, SummarizationModel},
+    resources::{LocalResource, Resource},
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Used when `SUMMARIZATION_MODEL_DIR` isn't set
+const DEFAULT_MODEL_DIR: &str = "models/bart-large-cnn";
+
+/// A BART summarization model, only loaded the first time a session asks for it
+pub struct LocalSummarizer {
+    model_dir: PathBuf,
+    model: tokio::sync::Mutex<Option<Arc<Mutex<SummarizationModel>>>>,
+}
+
+impl Default for LocalSummarizer {
+    fn default() -> LocalSummarizer {
+        LocalSummarizer::new(
+            std::env::var("SUMMARIZATION_MODEL_DIR")
+                .unwrap_or_else(|_| String::from(DEFAULT_MODEL_DIR)),
+        )
+    }
+}
+
+impl LocalSummarizer {
+    pub fn new(model_dir: impl Into<PathBuf>) -> LocalSummarizer {
+        LocalSummarizer {
+            model_dir: model_dir.into(),
+            model: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn model(&self) -> crate::error::Result<Arc<Mutex<SummarizationModel>>> {
+        let mut model = self.model.lock().await;
+        if let Some(model) = &*model {
+            return Ok(Arc::clone(model));
+        }
+        let resource = |file_name| {
+            Resource::Local(LocalResource {
+                local_path: self.model_dir.join(file_name),
+            })
+        };
+        let config = SummarizationConfig {
+            model_resource: resource("model.ot"),
+            config_resource: resource("config.json"),
+            vocab_resource: resource("vocab.json"),
+            merges_resource: resource("merges.txt"),
+            ..SummarizationConfig::default()
+        };
+        let loaded = tokio::task::spawn_blocking(move || SummarizationModel::new(config))
+            .await
+            .map_err(|why| crate::error::Error::Generation(why.to_string()))?
+            .map_err(|why| crate::error::Error::Generation(why.to_string()))?;
+        let loaded = Arc::new(Mutex::new(loaded));
+        *model = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    pub async fn summarize(&self, text: String) -> crate::error::Result<String> {
+        let model = self.model().await?;
+        let summaries = tokio::task::spawn_blocking(move || {
+            let model = model.lock().expect("Summarization model lock was poisoned");
+            model.summarize(&[&*text])
+        })
+        .await
+        .map_err(|why| crate::error::Error::Generation(why.to_string()))?;
+        summaries
+            .into_iter()
+            .next()
+            .ok_or_else(|| crate::error::Error::Generation(String::from("No summary")))
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -5,6 +7,83 @@ pub enum Error {
     #[error("Network error: {0}")]
     Surf(String),
 
+    #[error("Completion API returned HTTP {status}: {body}")]
+    Http { status: u16, body: String },
+
+    #[error("Rate limited by the completion API: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Completion API rejected our credentials: {0}")]
+    Unauthorized(String),
+
+    #[error("Completion API rejected the request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Could not decode completion response ({source}): {body}")]
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+
     #[error("Error formatting content: {0}")]
     Fmt(#[from] std::fmt::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Discord error: {0}")]
+    Discord(#[from] serenity::Error),
+
+    #[error("Local generation failed: {0}")]
+    Generation(String),
+
+    #[error("Error (de)serializing JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Error reading TOML: {0}")]
+    TomlDe(#[from] toml::de::Error),
+
+    #[error("Error writing TOML: {0}")]
+    TomlSer(#[from] toml::ser::Error),
+}
+
+impl Error {
+    /// Whether trying the same request again could succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Surf(_) | Error::RateLimited { .. } => true,
+            Error::Http { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// What to tell a channel when generating a reply failed. Raw response bodies stay in the
+    /// logs, since they can be entire HTML error pages
+    pub fn user_message(&self) -> String {
+        match self {
+            Error::Surf(_) => String::from("I couldn't reach the completion API, try again soon"),
+            Error::Http { status, .. } => {
+                format!("The completion API had a problem (HTTP {}), try again soon", status)
+            }
+            Error::RateLimited { .. } => String::from("I'm being rate limited, try again soon"),
+            Error::Unauthorized(_) => {
+                String::from("My completion API key was rejected, tell my owner")
+            }
+            Error::InvalidRequest(message) => {
+                format!("The completion API rejected my request: {}", message)
+            }
+            Error::Decode { .. } => String::from("The completion API sent something I can't read"),
+            _ => String::from("Something went wrong while generating a reply"),
+        }
+    }
 }
@@ -0,0 +1,286 @@
+/// This file runs reply generation on a bounded number of workers. Guilds take turns, so one busy
+/// guild can't keep everybody else waiting
+use crate::ChatTarget;
+use futures::future::{self, AbortHandle, BoxFuture};
+use serenity::model::id::GuildId;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_MAX_RUNNING: usize = 4;
+pub const DEFAULT_MAX_QUEUED: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Too many replies are waiting already, try again in a bit")]
+pub struct Busy;
+
+pub struct JobQueue {
+    max_running: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// One lane per guild (DMs share one), served round robin
+    lanes: VecDeque<Lane>,
+    queued: usize,
+    running: HashMap<ChatTarget, RunningJob>,
+    last_job_id: u64,
+}
+
+struct Lane {
+    guild_id: Option<GuildId>,
+    jobs: VecDeque<Job>,
+}
+
+struct Job {
+    chat_target: ChatTarget,
+    work: BoxFuture<'static, ()>,
+}
+
+struct RunningJob {
+    id: u64,
+    task: CancellableTask,
+}
+
+/// A spawned task that can be stopped from the outside
+pub struct CancellableTask {
+    abort_handle: AbortHandle,
+    handle: JoinHandle<()>,
+}
+
+impl CancellableTask {
+    pub fn spawn(work: impl Future<Output = ()> + Send + 'static) -> CancellableTask {
+        let (work, abort_handle) = future::abortable(work);
+        let handle = tokio::spawn(async move {
+            // being aborted is how cancelled tasks end, nothing to report
+            let _ = work.await;
+        });
+        CancellableTask {
+            abort_handle,
+            handle,
+        }
+    }
+
+    /// Stops the task at its next await, returning once it has stopped. Whatever it held (like a
+    /// session lock) is released by then
+    pub async fn cancel(self) {
+        self.abort_handle.abort();
+        if let Err(why) = self.handle.await {
+            eprintln!("Cancelled task failed: {}", &why);
+        }
+    }
+}
+
+impl JobQueue {
+    pub fn new(max_running: usize, max_queued: usize) -> JobQueue {
+        JobQueue {
+            max_running: max_running.max(1),
+            max_queued,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Whether `submit` would take another job right now
+    pub fn has_room(&self) -> bool {
+        self.state.lock().unwrap().queued < self.max_queued
+    }
+
+    /// Queues `work` for `chat_target`. A session that already has a job waiting doesn't get a
+    /// second one, the waiting job sees every line recorded by the time it runs
+    pub fn submit(
+        self: &Arc<Self>,
+        chat_target: ChatTarget,
+        work: BoxFuture<'static, ()>,
+    ) -> Result<(), Busy> {
+        {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            let already_queued = state
+                .lanes
+                .iter()
+                .flat_map(|lane| &lane.jobs)
+                .any(|job| job.chat_target == chat_target);
+            if already_queued {
+                return Ok(());
+            }
+            if state.queued >= self.max_queued {
+                return Err(Busy);
+            }
+            let guild_id = chat_target.guild_id;
+            let job = Job { chat_target, work };
+            match state
+                .lanes
+                .iter_mut()
+                .find(|lane| lane.guild_id == guild_id)
+            {
+                Some(lane) => lane.jobs.push_back(job),
+                None => state.lanes.push_back(Lane {
+                    guild_id,
+                    jobs: VecDeque::from(vec![job]),
+                }),
+            }
+            state.queued += 1;
+        }
+        self.start_jobs();
+        Ok(())
+    }
+
+    /// Drops `chat_target`'s waiting jobs and stops its running one, returning once it has
+    /// stopped
+    pub async fn cancel(self: &Arc<Self>, chat_target: &ChatTarget) {
+        let running = {
+            let mut state = self.state.lock().unwrap();
+            for lane in &mut state.lanes {
+                lane.jobs.retain(|job| job.chat_target != *chat_target);
+            }
+            state.lanes.retain(|lane| !lane.jobs.is_empty());
+            state.queued = state.lanes.iter().map(|lane| lane.jobs.len()).sum();
+            state.running.remove(chat_target)
+        };
+        if let Some(running) = running {
+            running.task.cancel().await;
+            // a cancelled job never finishes, so its worker is handed on here
+            self.start_jobs();
+        }
+    }
+
+    /// Starts waiting jobs until every worker is busy
+    fn start_jobs(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while state.running.len() < self.max_running {
+            let job = match state.next_job() {
+                Some(job) => job,
+                None => break,
+            };
+            state.last_job_id += 1;
+            let id = state.last_job_id;
+            let work = job.work;
+            let queue = Arc::clone(self);
+            let chat_target = job.chat_target.clone();
+            let task = CancellableTask::spawn(async move {
+                work.await;
+                queue.finish(&chat_target, id);
+            });
+            state
+                .running
+                .insert(job.chat_target, RunningJob { id, task });
+        }
+    }
+
+    fn finish(self: &Arc<Self>, chat_target: &ChatTarget, id: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            // a cancelled job's slot may already belong to a new job for the same session
+            if state.running.get(chat_target).map(|running| running.id) == Some(id) {
+                state.running.remove(chat_target);
+            }
+        }
+        self.start_jobs();
+    }
+}
+
+impl State {
+    /// Takes the next job from the lane whose turn it is, skipping sessions that already have a
+    /// job running so replies in a channel never race each other
+    fn next_job(&mut self) -> Option<Job> {
+        for _ in 0..self.lanes.len() {
+            let mut lane = self.lanes.pop_front()?;
+            let running = &self.running;
+            let job = lane
+                .jobs
+                .iter()
+                .position(|job| !running.contains_key(&job.chat_target))
+                .and_then(|index| lane.jobs.remove(index));
+            if !lane.jobs.is_empty() {
+                self.lanes.push_back(lane);
+            }
+            if job.is_some() {
+                self.queued -= 1;
+                return job;
+            }
+        }
+        None
+    }
+}
+
+/// Gives spawned tasks a chance to run until they're all waiting on something. Tests pause time,
+/// so that's as far as they get
+#[cfg(test)]
+pub async fn settle() {
+    for _ in 0..50 {
+        tokio::task::yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use serenity::model::id::ChannelId;
+    use tokio::sync::oneshot;
+
+    fn target(guild_id: u64, channel_id: u64) -> ChatTarget {
+        ChatTarget {
+            guild_id: Some(GuildId(guild_id)),
+            channel_id: ChannelId(channel_id),
+        }
+    }
+
+    /// A job that notes down its channel when it runs
+    fn note(ran: &Arc<Mutex<Vec<u64>>>, channel_id: u64) -> BoxFuture<'static, ()> {
+        let ran = Arc::clone(ran);
+        async move { ran.lock().unwrap().push(channel_id) }.boxed()
+    }
+
+    #[tokio::test]
+    async fn guilds_take_turns_and_the_queue_is_bounded() {
+        let queue = Arc::new(JobQueue::new(1, 3));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = oneshot::channel::<()>();
+        queue
+            .submit(target(1, 1), gate.map(|_| ()).boxed())
+            .expect("The queue is empty");
+        for &(guild_id, channel_id) in &[(1, 2), (1, 3), (2, 4)] {
+            queue
+                .submit(target(guild_id, channel_id), note(&ran, channel_id))
+                .expect("The queue has room");
+        }
+        assert!(queue.submit(target(3, 5), note(&ran, 5)).is_err());
+        assert!(queue.submit(target(1, 2), note(&ran, 2)).is_ok());
+
+        release.send(()).unwrap();
+        settle().await;
+        assert_eq!(*ran.lock().unwrap(), vec![2, 4, 3]);
+        assert!(queue.has_room());
+    }
+
+    #[tokio::test]
+    async fn cancelled_sessions_lose_their_jobs() {
+        let queue = Arc::new(JobQueue::new(2, 4));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let (_release, gate) = oneshot::channel::<()>();
+        queue
+            .submit(target(1, 1), gate.map(|_| ()).boxed())
+            .unwrap();
+        queue.submit(target(1, 1), note(&ran, 1)).unwrap();
+        queue.submit(target(1, 2), note(&ran, 2)).unwrap();
+        settle().await;
+        assert_eq!(
+            *ran.lock().unwrap(),
+            vec![2],
+            "Only one job runs at a time per session"
+        );
+
+        queue.cancel(&target(1, 1)).await;
+        settle().await;
+        assert_eq!(*ran.lock().unwrap(), vec![2]);
+        queue.submit(target(1, 1), note(&ran, 1)).unwrap();
+        settle().await;
+        assert_eq!(*ran.lock().unwrap(), vec![2, 1]);
+    }
+}
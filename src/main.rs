@@ -1,11 +1,19 @@
 // 1. dont do token estimation, the bot will break under better workloads
 // 2. dont respond to messages 1:1, itll branch the conversation sometimes and requires an extra api call to merge back together
-// 3. save contexts
-// 4. add more fine grained tuning permissions
+mod channel;
 mod commands;
+mod config;
+mod dispatch;
 mod engines;
 mod error;
+mod jobs;
+mod permissions;
+mod platform;
+mod repl;
+mod storage;
+mod tokenizer;
 mod transformers;
+mod usage;
 
 use engines::MessageSessionHandler;
 pub use engines::*;
@@ -17,40 +25,30 @@ use serenity::{
     http::Http,
     model::{
         channel::Message,
+        event::MessageUpdateEvent,
         gateway::Ready,
-        id::{ChannelId, GuildId},
+        id::{ChannelId, GuildId, MessageId, UserId},
     },
     prelude::*,
+    utils::ContentSafeOptions,
 };
 use std::{
     collections::{HashMap, HashSet},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    sync::Arc,
 };
-use tokio::{sync::mpsc, time};
 
 const COMMAND_IDENTIFIER: &str = "!";
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct ChatTarget {
-    guild_id: GuildId,
+    /// `None` for DMs
+    guild_id: Option<GuildId>,
     channel_id: ChannelId,
 }
 
-#[derive(Default)]
+/// Turns serenity events into platform independent ones for the [`dispatch::Dispatcher`]
 struct Handler {
-    session_map: ThreadsafeSessionMap,
-    /// Used for prolonging the delay for tasks that need to generate responses when multiple
-    /// people are talking, instead of responding 1:1
-    chat_timeout_map: RwLock<HashMap<ChatTarget, ChatTargetTimeoutCommunicator>>,
-    gpt3_token: String,
-}
-
-struct ChatTargetTimeoutCommunicator {
-    new_message_sender: mpsc::UnboundedSender<()>,
-    finished: Arc<AtomicBool>,
+    dispatcher: Arc<dispatch::Dispatcher>,
 }
 
 pub enum Session {
@@ -58,7 +56,17 @@ pub enum Session {
     GPT3(gpt3::GPT3MessageHandler),
 }
 
+/// What a reply is generated from, see [`Session::snapshot`]
+enum Snapshot {
+    GPT2(gpt2::GPT2MessageHandler),
+    GPT3(gpt3::Snapshot),
+}
+
 impl Session {
+    fn into_shared(self) -> SharedSession {
+        Arc::new(Mutex::new(self))
+    }
+
     async fn reset(&mut self, ctx: &Context, msg: &Message, args: Args) -> CommandResult {
         match self {
             Session::GPT2(session) => session.reset(ctx, msg, args).await,
@@ -79,191 +87,273 @@ impl Session {
             Session::GPT3(session) => session.info(ctx, msg, args).await,
         }
     }
-}
 
-impl std::fmt::Display for Session {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn clear(&mut self) {
         match self {
-            Session::GPT2(session) => write!(f, "{}", session),
-            Session::GPT3(session) => write!(f, "{}", session),
+            Session::GPT2(session) => session.clear(),
+            Session::GPT3(session) => session.clear(),
         }
     }
-}
 
-type ThreadsafeSessionMap = Arc<RwLock<HashMap<ChatTarget, Session>>>;
+    /// Takes the most recent reply out of the log
+    fn undo(
+        &mut self,
+        tokenizer: &tokenizer::Tokenizer,
+    ) -> Option<transformers::conversation::LogItem> {
+        match self {
+            Session::GPT2(session) => session.undo(),
+            Session::GPT3(session) => session.undo(tokenizer),
+        }
+    }
 
-fn get_chat_target_from_message(message: &Message) -> Option<ChatTarget> {
-    message.guild_id.map(|guild_id| ChatTarget {
-        guild_id,
-        channel_id: message.channel_id,
-    })
-}
+    /// Takes the last `count` lines out of the log
+    fn forget(
+        &mut self,
+        count: usize,
+        tokenizer: &tokenizer::Tokenizer,
+    ) -> Vec<transformers::conversation::LogItem> {
+        match self {
+            Session::GPT2(session) => session.forget(count),
+            Session::GPT3(session) => session.forget(count, tokenizer),
+        }
+    }
 
-impl Handler {
-    fn new(gpt3_token: String) -> (Handler, ThreadsafeSessionMap) {
-        let session_map = Arc::new(RwLock::new(HashMap::new()));
-        (
-            Handler {
-                session_map: Arc::clone(&session_map),
-                chat_timeout_map: RwLock::new(HashMap::new()),
-                gpt3_token,
-            },
-            session_map,
-        )
+    /// Updates or drops the line someone sent as `message_id`, returning whether there was one
+    fn edit_line(
+        &mut self,
+        message_id: MessageId,
+        text: Option<&str>,
+        tokenizer: &tokenizer::Tokenizer,
+    ) -> bool {
+        match self {
+            Session::GPT2(session) => session.edit_line(message_id, text),
+            Session::GPT3(session) => session.edit_line(message_id, text, tokenizer),
+        }
     }
 
-    async fn should_respond_to_target(&self, chat_target: &ChatTarget) -> bool {
-        self.session_map.read().await.contains_key(chat_target)
+    /// Drops the lines for `message_ids`, returning how many there were
+    fn remove_lines(
+        &mut self,
+        message_ids: &[MessageId],
+        tokenizer: &tokenizer::Tokenizer,
+    ) -> usize {
+        match self {
+            Session::GPT2(session) => session.remove_lines(message_ids),
+            Session::GPT3(session) => session.remove_lines(message_ids, tokenizer),
+        }
+    }
+
+    fn channel_settings(&self) -> &channel::ChannelSettings {
+        match self {
+            Session::GPT2(session) => &session.channel_settings,
+            Session::GPT3(session) => &session.channel_settings,
+        }
     }
-}
 
-async fn timeout_task(mut payload: TimeoutTaskPayload) {
-    let wait_dur = time::Duration::from_millis(2_500);
-    let mut delay = time::delay_for(wait_dur);
-    loop {
-        eprintln!("selecting");
-        tokio::select! {
-            _ = &mut delay => break,
-            _ = payload.new_message_receiver.recv() => {
-                delay.reset(time::Instant::now() + time::Duration::from_millis(1_500));
+    fn ai_name(&self) -> &str {
+        match self {
+            Session::GPT2(session) => session.transformer.get_ai_name(),
+            Session::GPT3(session) => session.transformer.get_ai_name(),
+        }
+    }
+
+    /// Applies `tuning`, unless it goes past what the guild (or DMs) are configured to allow
+    fn tune(
+        &mut self,
+        tuning: Tuning,
+        rules: Option<&config::EngineRules>,
+    ) -> Result<(), commands::StringError> {
+        match self {
+            Session::GPT2(session) => session.tune(tuning),
+            Session::GPT3(session) => {
+                if let Some(rules) = rules {
+                    session.check_limits(&tuning, rules)?;
+                }
+                session.tune(tuning)
             }
         }
     }
-    eprintln!("do work now!");
-    payload.finished_flag.store(true, Ordering::SeqCst);
-    // 0. start typing
-    // 1. turn session into string, template out to prompt model
-    // 2. request completion
-    // 3. add ai generated line
-    // 4. send ai generated line as response
-    // ???
-    // profit
-    let http = payload.http;
-    if let Err(why) = payload.channel_id.broadcast_typing(&http).await {
-        eprintln!("Failed to broadcast typing: {:?}", &why);
+
+    /// Copies the session out, so a reply can be generated without keeping it locked
+    fn snapshot(&self) -> Snapshot {
+        match self {
+            Session::GPT2(session) => Snapshot::GPT2(session.clone()),
+            Session::GPT3(session) => Snapshot::GPT3(session.snapshot()),
+        }
     }
 
-    let mut session_map_write = payload.session_map.write().await;
-    let mut session = if let Some(mut session) = session_map_write.get_mut(&payload.chat_target) {
-        session
-    } else {
-        eprintln!("Failed to find session in map after timeout");
-        return;
-    };
-    match session {
-        Session::GPT2(session) => {}
-        Session::GPT3(session) => {
-            let gpt3_payload = gpt3::Payload {
-                token: payload.gpt3_token.clone(),
-                channel_id: payload.channel_id,
-            };
-            session.perform_work(&http, gpt3_payload).await;
+    /// Takes in the reply that was generated from `snapshot`, after any lines recorded meanwhile
+    fn commit(
+        &mut self,
+        snapshot: Snapshot,
+        reply: Option<transformers::conversation::LogItem>,
+        tokenizer: &tokenizer::Tokenizer,
+    ) {
+        match (self, snapshot) {
+            (Session::GPT2(session), Snapshot::GPT2(_)) => {
+                if let Some(reply) = reply {
+                    if let Err(why) = session.record(reply, tokenizer) {
+                        eprintln!("Failed to record reply: {}", &why);
+                    }
+                }
+            }
+            (Session::GPT3(session), Snapshot::GPT3(snapshot)) => {
+                session.commit(snapshot, reply, tokenizer)
+            }
+            _ => eprintln!("Session changed engines while a reply was generated"),
+        }
+    }
+
+    /// Hands over what the session spent on completions since the last call
+    fn take_usage(&mut self) -> usage::Tally {
+        match self {
+            Session::GPT2(_) => usage::Tally::default(),
+            Session::GPT3(session) => session.take_usage(),
+        }
+    }
+
+    /// Clamps the session's settings to what its guild (or DMs) are configured to allow
+    fn apply_limits(&mut self, rules: &config::EngineRules) {
+        match self {
+            Session::GPT2(_) => {}
+            Session::GPT3(session) => session.apply_limits(rules),
+        }
+    }
+
+    fn save(&self) -> storage::SavedSession {
+        match self {
+            Session::GPT2(session) => storage::SavedSession::GPT2(session.save()),
+            Session::GPT3(session) => storage::SavedSession::GPT3(session.save()),
+        }
+    }
+
+    fn restore(
+        saved: storage::SavedSession,
+        completion_provider: &Arc<dyn openai::CompletionProvider>,
+    ) -> Session {
+        match saved {
+            storage::SavedSession::GPT2(saved) => {
+                Session::GPT2(gpt2::GPT2MessageHandler::restore(saved))
+            }
+            storage::SavedSession::GPT3(saved) => {
+                Session::GPT3(gpt3::GPT3MessageHandler::restore(
+                    saved,
+                    Arc::clone(completion_provider),
+                ))
+            }
         }
     }
 }
 
-struct TimeoutTaskPayload {
-    new_message_receiver: mpsc::UnboundedReceiver<()>,
-    channel_id: ChannelId,
-    finished_flag: Arc<AtomicBool>,
-    gpt3_token: String,
-    http: Arc<Http>,
-    session_map: ThreadsafeSessionMap,
-    chat_target: ChatTarget,
+impl std::fmt::Display for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Session::GPT2(session) => write!(f, "{}", session),
+            Session::GPT3(session) => write!(f, "{}", session),
+        }
+    }
+}
+
+/// Every session has its own lock, so a slow reply in one channel doesn't hold up the others
+type SharedSession = Arc<Mutex<Session>>;
+/// Only locked long enough to find, add or remove a session, never while one is in use
+type ThreadsafeSessionMap = Arc<RwLock<HashMap<ChatTarget, SharedSession>>>;
+
+/// Clones the handle for `chat_target`'s session out of the map, releasing the map right away
+async fn get_session(
+    session_map: &ThreadsafeSessionMap,
+    chat_target: &ChatTarget,
+) -> Option<SharedSession> {
+    session_map.read().await.get(chat_target).map(Arc::clone)
+}
+
+fn get_chat_target_from_message(message: &Message) -> ChatTarget {
+    ChatTarget {
+        guild_id: message.guild_id,
+        channel_id: message.channel_id,
+    }
 }
 
 // async_trait is pretty gnarly with lifetimes :(
 #[serenity::async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, message: Message) {
-        if message.author.bot || message.content.starts_with(COMMAND_IDENTIFIER) {
+        let chat_target = get_chat_target_from_message(&message);
+        // content_safe has to resolve mentions, so skip it for channels without a session
+        if !self.dispatcher.should_respond_to_target(&chat_target).await {
             return;
         }
-        let chat_target = match get_chat_target_from_message(&message) {
-            Some(target) => target,
-            None => return,
+        let platform = Arc::new(platform::Discord::new(
+            Arc::clone(&ctx.cache),
+            Arc::clone(&ctx.http),
+        ));
+        let incoming = dispatch::IncomingMessage {
+            chat_target,
+            message_id: message.id,
+            author_id: message.author.id,
+            author_name: message.author.name.clone(),
+            author_is_bot: message.author.bot,
+            mentions_bot: message.mentions_user_id(ctx.cache.current_user_id().await),
+            reply_to: message
+                .message_reference
+                .as_ref()
+                .and_then(|reference| reference.message_id),
+            content: message.content_safe(&ctx).await,
         };
+        self.dispatcher.handle_message(platform, incoming).await;
+    }
 
-        if !self.should_respond_to_target(&chat_target).await {
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // our own replies get edited while they stream in
+        if event.author.as_ref().map_or(false, |author| author.bot) {
             return;
         }
+        // updates without content are embeds resolving and the like
+        let content = match event.content {
+            Some(content) => content,
+            None => return,
+        };
+        let chat_target = match self.dispatcher.chat_target_in(event.channel_id).await {
+            Some(chat_target) => chat_target,
+            None => return,
+        };
+        let content =
+            serenity::utils::content_safe(&ctx.cache, &content, &ContentSafeOptions::default())
+                .await;
+        self.dispatcher
+            .handle_edit(&chat_target, event.id, &content)
+            .await;
+    }
 
-        if !message.content.starts_with(">") {
-            return;
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+    ) {
+        if let Some(chat_target) = self.dispatcher.chat_target_in(channel_id).await {
+            self.dispatcher
+                .handle_delete(&chat_target, &[deleted_message_id])
+                .await;
         }
+    }
 
-        let mut session_map_write = self.session_map.write().await;
-        if let Some(ref mut session) = session_map_write.get_mut(&chat_target) {
-            let author_nick = message.author_nick(&ctx).await;
-            // TODO(haze): bad haze, bad code
-            let text = message
-                .content_safe(&ctx)
-                .await
-                .trim_start_matches(">")
-                .to_string();
-            match session {
-                Session::GPT2(session) => {}
-                Session::GPT3(session) => {
-                    if let Err(why) = session
-                        .record(
-                            transformers::conversation::LogItem {
-                                author_name: Some(message.author.name.clone()),
-                                sent_by_ai: false,
-                                author_nick,
-                                text,
-                            },
-                            &*self.gpt3_token,
-                        )
-                        .await
-                    {
-                        eprintln!("Failed to record line: {:?}, {:?}", message.content, why);
-                    } else {
-                        eprintln!("token count: {}", session.token_count);
-                    }
-                }
-            }
-        }
-        drop(session_map_write);
-
-        let timeout_map_read = self.chat_timeout_map.read().await;
-        if let Some(Some(sender)) = timeout_map_read.get(&chat_target).map(|sender| {
-            if sender.finished.load(Ordering::SeqCst) {
-                None
-            } else {
-                Some(sender)
-            }
-        }) {
-            if let Err(why) = sender.new_message_sender.send(()) {
-                eprintln!("Failed to send prolonging message: {:?}", &why);
-            }
-        } else {
-            drop(timeout_map_read);
-            let mut timeout_map_write = self.chat_timeout_map.write().await;
-            // first message, and we aren't waiting on a timeout
-            // bounded by discord on the network side
-            let (tx, rx) = mpsc::unbounded_channel();
-            // TODO(haze): collect task handle
-            let finished_flag = Arc::new(AtomicBool::default());
-            timeout_map_write.insert(
-                chat_target.clone(),
-                ChatTargetTimeoutCommunicator {
-                    new_message_sender: tx,
-                    finished: Arc::clone(&finished_flag),
-                },
-            );
-            tokio::spawn(timeout_task(TimeoutTaskPayload {
-                session_map: Arc::clone(&self.session_map),
-                chat_target: chat_target.clone(),
-                channel_id: message.channel_id,
-                gpt3_token: self.gpt3_token.clone(),
-                http: Arc::clone(&ctx.http),
-                new_message_receiver: rx,
-                finished_flag,
-            }));
-            let session_map_read = self.session_map.read().await;
-            if let Some(ref session) = session_map_read.get(&chat_target) {
-                eprintln!("enabled {}", session);
-            }
+    async fn message_delete_bulk(
+        &self,
+        _ctx: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+    ) {
+        if let Some(chat_target) = self.dispatcher.chat_target_in(channel_id).await {
+            self.dispatcher
+                .handle_delete(&chat_target, &multiple_deleted_messages_ids)
+                .await;
         }
     }
 
@@ -277,13 +367,89 @@ impl TypeMapKey for SessionMapKey {
     type Value = ThreadsafeSessionMap;
 }
 
+pub struct StorageKey;
+impl TypeMapKey for StorageKey {
+    type Value = Arc<storage::Storage>;
+}
+
+pub struct PermissionsKey;
+impl TypeMapKey for PermissionsKey {
+    type Value = Arc<RwLock<permissions::Permissions>>;
+}
+
+pub struct ConfigKey;
+impl TypeMapKey for ConfigKey {
+    type Value = Arc<RwLock<config::Config>>;
+}
+
+pub struct UsageKey;
+impl TypeMapKey for UsageKey {
+    type Value = Arc<RwLock<usage::Ledger>>;
+}
+
+pub struct CompletionProviderKey;
+impl TypeMapKey for CompletionProviderKey {
+    type Value = Arc<dyn openai::CompletionProvider>;
+}
+
+pub struct DispatcherKey;
+impl TypeMapKey for DispatcherKey {
+    type Value = Arc<dispatch::Dispatcher>;
+}
+
+pub struct OwnersKey;
+impl TypeMapKey for OwnersKey {
+    type Value = HashSet<UserId>;
+}
+
+fn completion_provider(gpt3_token: String) -> Arc<dyn openai::CompletionProvider> {
+    // anything that speaks the OpenAI API works here, e.g. a local server
+    let base_url = std::env::var("COMPLETIONS_BASE_URL")
+        .unwrap_or_else(|_| String::from(openai::DEFAULT_BASE_URL));
+    Arc::new(openai::OpenAI::new(gpt3_token, base_url))
+}
+
+/// Reads a number from the environment, falling back to `default` if it's missing or malformed
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     dotenv::dotenv().ok();
+    let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| String::from("data"));
+    let tokenizer_dir =
+        std::env::var("TOKENIZER_DIR").unwrap_or_else(|_| format!("{}/tokenizer", data_dir));
+    // every mode counts tokens, so this has to load before anything else. The docker image ships
+    // GPT-2's files, anywhere else they have to be downloaded first
+    let tokenizer = match tokenizer::Tokenizer::from_dir(&tokenizer_dir) {
+        Ok(tokenizer) => Arc::new(tokenizer),
+        Err(why) => {
+            return Err(format!(
+                "Could not load the tokenizer from {} ({}). It needs GPT-2's vocab.json and \
+                 merges.txt, set TOKENIZER_DIR to the directory holding them",
+                tokenizer_dir, why
+            )
+            .into())
+        }
+    };
+
+    let mut cli_args = std::env::args().skip(1);
+    if cli_args.next().as_deref() == Some("repl") {
+        // local servers usually don't check the key, so it's optional here
+        let gpt3_token = std::env::var("GPT3_TOKEN").unwrap_or_default();
+        return repl::run(cli_args, tokenizer, completion_provider(gpt3_token)).await;
+    }
+
     // 1. get discord and gpt3 keys from environment
     let discord_token =
         std::env::var("DISCORD_TOKEN").expect("Could not find discord token in environment");
     let gpt3_token = std::env::var("GPT3_TOKEN").expect("Could not find gpt3 token in environment");
+    let completion_provider = completion_provider(gpt3_token);
+    let storage = Arc::new(storage::Storage::new(&data_dir)?);
 
     let http = Http::new_with_token(&discord_token);
 
@@ -308,13 +474,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .delimiters(vec![" ", "\n"])
                 .on_mention(Some(bot_id))
                 .prefix(COMMAND_IDENTIFIER)
-                .owners(owners)
+                .owners(owners.clone())
         })
+        .before(commands::before)
         .after(commands::after)
-        .group(&commands::ADMIN_GROUP);
+        .group(&commands::ADMIN_GROUP)
+        .group(&commands::OWNER_GROUP)
+        .group(&commands::CONVERSATIONTUNING_GROUP);
 
     // start serenity bot
-    let (handler, session_map) = Handler::new(gpt3_token);
+    let config = storage.load_config()?;
+    let session_map: ThreadsafeSessionMap = Arc::new(RwLock::new(HashMap::new()));
+    {
+        let saved_sessions = storage.load_sessions(&completion_provider)?;
+        eprintln!("Restored {} sessions", saved_sessions.len());
+        let mut session_map_write = session_map.write().await;
+        for (chat_target, mut session) in saved_sessions {
+            // the limits may have been lowered while the bot was down
+            if let Some(rules) = config.rules(chat_target.guild_id) {
+                session.apply_limits(rules);
+            }
+            session_map_write.insert(chat_target, session.into_shared());
+        }
+    }
+    let config = Arc::new(RwLock::new(config));
+    let usage = Arc::new(RwLock::new(storage.load_usage()?));
+    let dispatcher = Arc::new(dispatch::Dispatcher::new(
+        Arc::clone(&session_map),
+        tokenizer,
+        Arc::clone(&storage),
+        jobs::JobQueue::new(
+            env_or("MAX_RUNNING_REPLIES", jobs::DEFAULT_MAX_RUNNING),
+            env_or("MAX_QUEUED_REPLIES", jobs::DEFAULT_MAX_QUEUED),
+        ),
+        Arc::clone(&config),
+        Arc::clone(&usage),
+    ));
+    let handler = Handler {
+        dispatcher: Arc::clone(&dispatcher),
+    };
     let mut client = Client::new(&discord_token)
         .event_handler(handler)
         .framework(framework)
@@ -323,6 +521,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     {
         let mut data = client.data.write().await;
         data.insert::<SessionMapKey>(session_map);
+        data.insert::<DispatcherKey>(dispatcher);
+        data.insert::<PermissionsKey>(Arc::new(RwLock::new(storage.load_permissions()?)));
+        data.insert::<ConfigKey>(config);
+        data.insert::<UsageKey>(usage);
+        data.insert::<OwnersKey>(owners);
+        data.insert::<CompletionProviderKey>(completion_provider);
+        data.insert::<StorageKey>(storage);
     }
 
     client.start().await?;
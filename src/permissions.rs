@@ -0,0 +1,215 @@
+/// This file decides who may manage sessions. Owners and guild administrators can do everything,
+/// everyone else needs a capability granted to them (or one of their roles) per guild
+use serenity::model::id::{GuildId, RoleId, UserId};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    Enable,
+    Disable,
+    Reset,
+    Tune,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [
+        Capability::Enable,
+        Capability::Disable,
+        Capability::Reset,
+        Capability::Tune,
+    ];
+}
+
+impl std::str::FromStr for Capability {
+    type Err = crate::commands::StringError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "enable" => Ok(Capability::Enable),
+            "disable" => Ok(Capability::Disable),
+            "reset" => Ok(Capability::Reset),
+            "tune" => Ok(Capability::Tune),
+            _ => Err(format!("Unknown capability `{}`", s).into()),
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Enable => "enable",
+            Capability::Disable => "disable",
+            Capability::Reset => "reset",
+            Capability::Tune => "tune",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What it takes to run a command
+#[derive(Debug, PartialEq)]
+pub enum Requirement {
+    Nothing,
+    Capability(Capability),
+    /// Only owners and guild administrators, never grantable
+    Administrator,
+    /// Only owners
+    Owner,
+}
+
+impl Requirement {
+    pub fn for_command(command_name: &str) -> Requirement {
+        match command_name {
+            "info" | "usage" => Requirement::Nothing,
+            "enable" => Requirement::Capability(Capability::Enable),
+            "disable" => Requirement::Capability(Capability::Disable),
+            "reset" | "undo" | "retry" | "forget" => Requirement::Capability(Capability::Reset),
+            "temperature" | "top_p" | "presence_penalty" | "frequency_penalty" | "max_tokens"
+            | "engine" | "context" | "retries" | "mode" | "streaming" | "summarizer"
+            | "trigger" | "listen" | "first_delay" | "follow_up_delay" | "max_delay"
+            | "reply_gap" => Requirement::Capability(Capability::Tune),
+            "allow" | "deny" => Requirement::Owner,
+            // anything not listed here is locked down until someone decides otherwise
+            _ => Requirement::Administrator,
+        }
+    }
+}
+
+/// Who a grant applies to
+#[derive(Debug, Clone, Copy)]
+pub enum Grantee {
+    Role(RoleId),
+    User(UserId),
+}
+
+impl std::fmt::Display for Grantee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Grantee::Role(role_id) => write!(f, "<@&{}>", role_id),
+            Grantee::User(user_id) => write!(f, "<@{}>", user_id),
+        }
+    }
+}
+
+/// Grants for a single guild, keyed by raw ids so they serialize as plain JSON objects
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GuildGrants {
+    pub roles: HashMap<u64, HashSet<Capability>>,
+    pub users: HashMap<u64, HashSet<Capability>>,
+}
+
+impl GuildGrants {
+    fn grants_for(&mut self, grantee: Grantee) -> &mut HashSet<Capability> {
+        match grantee {
+            Grantee::Role(role_id) => self.roles.entry(role_id.0).or_default(),
+            Grantee::User(user_id) => self.users.entry(user_id.0).or_default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.roles.is_empty() && self.users.is_empty()
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    pub guilds: HashMap<u64, GuildGrants>,
+}
+
+impl Permissions {
+    pub fn allows(
+        &self,
+        guild_id: GuildId,
+        user_id: UserId,
+        roles: &[RoleId],
+        capability: Capability,
+    ) -> bool {
+        let guild = match self.guilds.get(&guild_id.0) {
+            Some(guild) => guild,
+            None => return false,
+        };
+        let has = |grants: Option<&HashSet<Capability>>| {
+            grants.map_or(false, |grants| grants.contains(&capability))
+        };
+        has(guild.users.get(&user_id.0))
+            || roles
+                .iter()
+                .any(|role_id| has(guild.roles.get(&role_id.0)))
+    }
+
+    pub fn grant(&mut self, guild_id: GuildId, grantee: Grantee, capabilities: &[Capability]) {
+        self.guilds
+            .entry(guild_id.0)
+            .or_default()
+            .grants_for(grantee)
+            .extend(capabilities.iter().copied());
+    }
+
+    /// Removes `capabilities` from `grantee`, or everything they had if none are given
+    pub fn revoke(&mut self, guild_id: GuildId, grantee: Grantee, capabilities: &[Capability]) {
+        let guild = match self.guilds.get_mut(&guild_id.0) {
+            Some(guild) => guild,
+            None => return,
+        };
+        let grants = guild.grants_for(grantee);
+        if capabilities.is_empty() {
+            grants.clear();
+        } else {
+            grants.retain(|capability| !capabilities.contains(capability));
+        }
+        guild.roles.retain(|_, grants| !grants.is_empty());
+        guild.users.retain(|_, grants| !grants.is_empty());
+        if guild.is_empty() {
+            self.guilds.remove(&guild_id.0);
+        }
+    }
+
+    pub fn describe(&self, guild_id: GuildId) -> Vec<(Grantee, Vec<Capability>)> {
+        let guild = match self.guilds.get(&guild_id.0) {
+            Some(guild) => guild,
+            None => return Vec::new(),
+        };
+        let sorted = |grants: &HashSet<Capability>| {
+            let mut grants: Vec<Capability> = grants.iter().copied().collect();
+            grants.sort_by_key(|capability| capability.to_string());
+            grants
+        };
+        guild
+            .roles
+            .iter()
+            .map(|(role_id, grants)| (Grantee::Role(RoleId(*role_id)), sorted(grants)))
+            .chain(
+                guild
+                    .users
+                    .iter()
+                    .map(|(user_id, grants)| (Grantee::User(UserId(*user_id)), sorted(grants))),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants_apply_through_roles_and_users() {
+        let guild_id = GuildId(1);
+        let mut permissions = Permissions::default();
+        permissions.grant(guild_id, Grantee::Role(RoleId(10)), &[Capability::Tune]);
+        permissions.grant(guild_id, Grantee::User(UserId(20)), &[Capability::Reset]);
+
+        assert!(permissions.allows(guild_id, UserId(30), &[RoleId(10)], Capability::Tune));
+        assert!(!permissions.allows(guild_id, UserId(30), &[RoleId(10)], Capability::Reset));
+        assert!(permissions.allows(guild_id, UserId(20), &[], Capability::Reset));
+        assert!(!permissions.allows(GuildId(2), UserId(20), &[], Capability::Reset));
+
+        permissions.revoke(guild_id, Grantee::User(UserId(20)), &[]);
+        assert!(!permissions.allows(guild_id, UserId(20), &[], Capability::Reset));
+        permissions.revoke(guild_id, Grantee::Role(RoleId(10)), &[Capability::Tune]);
+        assert!(permissions.guilds.is_empty());
+    }
+}
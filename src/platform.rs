@@ -0,0 +1,247 @@
+/// This file is the seam between the message pipeline and Discord. The pipeline only ever talks to
+/// a [`ChatPlatform`], so it can be driven by an in-memory one in tests
+use serenity::{
+    cache::Cache,
+    http::Http,
+    model::id::{ChannelId, GuildId, MessageId, UserId},
+};
+use std::sync::Arc;
+
+#[serenity::async_trait]
+pub trait ChatPlatform: Send + Sync {
+    /// Sends `text` as is, without any mention escaping
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+    ) -> crate::error::Result<MessageId>;
+    /// Replaces the text of one of our own messages, again without escaping
+    async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+    ) -> crate::error::Result<()>;
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> crate::error::Result<()>;
+    async fn broadcast_typing(&self, channel_id: ChannelId) -> crate::error::Result<()>;
+    async fn react(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        reaction: char,
+    ) -> crate::error::Result<()>;
+    /// The user's nickname in `guild_id`, if they have one
+    async fn resolve_nick(&self, guild_id: GuildId, user_id: UserId) -> Option<String>;
+    /// Whether the bot wrote `message_id`, even if it's long gone from every log
+    async fn sent_by_us(&self, channel_id: ChannelId, message_id: MessageId) -> bool;
+}
+
+pub struct Discord {
+    cache: Arc<Cache>,
+    http: Arc<Http>,
+}
+
+impl Discord {
+    pub fn new(cache: Arc<Cache>, http: Arc<Http>) -> Discord {
+        Discord { cache, http }
+    }
+}
+
+#[serenity::async_trait]
+impl ChatPlatform for Discord {
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+    ) -> crate::error::Result<MessageId> {
+        let message = channel_id
+            .send_message(&*self.http, |m| m.content(text))
+            .await?;
+        Ok(message.id)
+    }
+
+    async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+    ) -> crate::error::Result<()> {
+        channel_id
+            .edit_message(&*self.http, message_id, |m| m.content(text))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> crate::error::Result<()> {
+        Ok(channel_id.delete_message(&*self.http, message_id).await?)
+    }
+
+    async fn broadcast_typing(&self, channel_id: ChannelId) -> crate::error::Result<()> {
+        Ok(channel_id.broadcast_typing(&*self.http).await?)
+    }
+
+    async fn react(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        reaction: char,
+    ) -> crate::error::Result<()> {
+        channel_id
+            .create_reaction(&*self.http, message_id, reaction)
+            .await?;
+        Ok(())
+    }
+
+    async fn resolve_nick(&self, guild_id: GuildId, user_id: UserId) -> Option<String> {
+        match guild_id
+            .member((&self.cache, &*self.http), user_id)
+            .await
+        {
+            Ok(member) => member.nick,
+            Err(why) => {
+                eprintln!("Failed to look up member {}: {}", user_id, &why);
+                None
+            }
+        }
+    }
+
+    async fn sent_by_us(&self, channel_id: ChannelId, message_id: MessageId) -> bool {
+        let message = match self.cache.message(channel_id, message_id).await {
+            Some(message) => message,
+            None => match self.http.get_message(channel_id.0, message_id.0).await {
+                Ok(message) => message,
+                Err(why) => {
+                    eprintln!("Failed to look up message {}: {}", message_id, &why);
+                    return false;
+                }
+            },
+        };
+        message.author.id == self.cache.current_user_id().await
+    }
+}
+
+/// Everything recorded by [`InMemory`], in the order it happened
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Message { channel_id: ChannelId, text: String },
+    Edit { message_id: MessageId, text: String },
+    Delete { message_id: MessageId },
+    Typing { channel_id: ChannelId },
+    Reaction { message_id: MessageId, reaction: char },
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct InMemory {
+    events: std::sync::Mutex<Vec<Event>>,
+    last_message_id: std::sync::atomic::AtomicU64,
+    nicks: std::collections::HashMap<(GuildId, UserId), String>,
+}
+
+#[cfg(test)]
+impl InMemory {
+    pub fn with_nick(mut self, guild_id: GuildId, user_id: UserId, nick: &str) -> InMemory {
+        self.nicks.insert((guild_id, user_id), nick.to_string());
+        self
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("Event log poisoned").clone()
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.events()
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Message { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn push(&self, event: Event) {
+        self.events.lock().expect("Event log poisoned").push(event);
+    }
+}
+
+#[cfg(test)]
+#[serenity::async_trait]
+impl ChatPlatform for InMemory {
+    /// Messages are numbered from 1 in the order they're sent
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+    ) -> crate::error::Result<MessageId> {
+        self.push(Event::Message {
+            channel_id,
+            text: text.to_string(),
+        });
+        let id = self
+            .last_message_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            + 1;
+        Ok(MessageId(id))
+    }
+
+    async fn edit_message(
+        &self,
+        _channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+    ) -> crate::error::Result<()> {
+        self.push(Event::Edit {
+            message_id,
+            text: text.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        _channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> crate::error::Result<()> {
+        self.push(Event::Delete { message_id });
+        Ok(())
+    }
+
+    async fn broadcast_typing(&self, channel_id: ChannelId) -> crate::error::Result<()> {
+        self.push(Event::Typing { channel_id });
+        Ok(())
+    }
+
+    async fn react(
+        &self,
+        _channel_id: ChannelId,
+        message_id: MessageId,
+        reaction: char,
+    ) -> crate::error::Result<()> {
+        self.push(Event::Reaction {
+            message_id,
+            reaction,
+        });
+        Ok(())
+    }
+
+    async fn resolve_nick(&self, guild_id: GuildId, user_id: UserId) -> Option<String> {
+        self.nicks.get(&(guild_id, user_id)).cloned()
+    }
+
+    /// Only ids that were handed out by `send_message` are ours
+    async fn sent_by_us(&self, _channel_id: ChannelId, message_id: MessageId) -> bool {
+        message_id.0
+            <= self
+                .last_message_id
+                .load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
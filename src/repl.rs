@@ -0,0 +1,348 @@
+/// This file is a terminal frontend for sessions, so personas can be iterated on without deploying
+/// to Discord. Started with `dorothy repl [options]`
+use crate::{
+    commands::{self, StringError},
+    engines::{gpt2, gpt3, openai::CompletionProvider, summarization::LocalSummarizer},
+    storage,
+    tokenizer::Tokenizer,
+    transformers::{conversation, TransformerKind},
+    Session, Tuning,
+};
+use serenity::{
+    framework::standard::{Args, Delimiter},
+    model::id::ChannelId,
+};
+use std::{fs, io::Write, sync::Arc};
+
+const USAGE: &str = "\
+usage: dorothy repl [--engine gpt3|gpt2] [--model <engine or model dir>] [--name <ai name>]
+                    [--context <context>] [--load <session file>]";
+
+const HELP: &str = "\
+Lines are recorded as the current speaker, an empty line asks for a reply.
+  /as <name>              speak as someone else
+  /prompt                 show the prompt the next reply would be generated from
+  /reply                  ask for a reply
+  /set <setting> <value>  change a setting, same as the `!set` commands
+  /undo                   take back the last reply
+  /retry                  take back the last reply and ask for a new one
+  /forget <count>         drop the last lines of the conversation
+  /reset                  forget the conversation
+  /save <file>            save the session
+  /load <file>            load a saved session
+  /quit                   leave";
+
+/// Replies aren't sent anywhere, but engines want a channel to put in their payload
+const REPL_CHANNEL: ChannelId = ChannelId(0);
+
+#[derive(Debug)]
+struct Options {
+    engine: String,
+    model: Option<String>,
+    ai_name: String,
+    context: Option<String>,
+    load: Option<String>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, StringError> {
+    let mut options = Options {
+        engine: String::from("gpt3"),
+        model: None,
+        ai_name: String::from("Dorothy"),
+        context: None,
+        load: None,
+    };
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| StringError::from(format!("{} needs a value\n{}", flag, USAGE)))
+        };
+        match &*flag {
+            "--engine" => options.engine = value()?.to_lowercase(),
+            "--model" => options.model = Some(value()?),
+            "--name" => options.ai_name = value()?,
+            "--context" => options.context = Some(value()?),
+            "--load" => options.load = Some(value()?),
+            _ => return Err(format!("Unknown option `{}`\n{}", flag, USAGE).into()),
+        }
+    }
+    Ok(options)
+}
+
+/// Parses what follows `/set`, the setting's name and then its value like the `!set` commands
+fn parse_set(rest: &str) -> Result<Tuning, StringError> {
+    let mut args = Args::new(rest, &[Delimiter::Single(' ')]);
+    let setting = args.single::<String>()?;
+    commands::parse_tuning(&setting, &mut args)
+}
+
+fn new_session(
+    options: &Options,
+    provider: &Arc<dyn CompletionProvider>,
+) -> Result<Session, StringError> {
+    let mut transformer = TransformerKind::Conversation(conversation::Transformer {
+        ai_name: options.ai_name.clone(),
+        context: None,
+        summary: None,
+    });
+    if let Some(context) = &options.context {
+        transformer.set_context(context);
+    }
+    Ok(match &*options.engine {
+        "gpt3" => {
+            let mut handler = gpt3::GPT3MessageHandler::new(transformer, Arc::clone(provider));
+            let engine = options
+                .model
+                .clone()
+                .unwrap_or_else(|| String::from(gpt3::DEFAULT_ENGINE));
+            handler.request_mode = gpt3::RequestMode::for_engine(&engine);
+            handler.set_engine(engine);
+            Session::GPT3(handler)
+        }
+        "gpt2" => {
+            let mut handler = gpt2::GPT2MessageHandler::new(transformer);
+            if let Some(model_dir) = &options.model {
+                handler.set_model_dir(model_dir.into());
+            }
+            Session::GPT2(handler)
+        }
+        engine => return Err(format!("Unknown engine `{}`\n{}", engine, USAGE).into()),
+    })
+}
+
+struct Repl {
+    session: Session,
+    speaker: String,
+    provider: Arc<dyn CompletionProvider>,
+    tokenizer: Arc<Tokenizer>,
+    local_summarizer: Arc<LocalSummarizer>,
+    gpt2_generators: Arc<gpt2::GeneratorCache>,
+}
+
+pub async fn run(
+    args: impl Iterator<Item = String>,
+    tokenizer: Arc<Tokenizer>,
+    provider: Arc<dyn CompletionProvider>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let options = parse_options(args)?;
+    let session = match &options.load {
+        Some(path) => load_session(path, &provider)?,
+        None => new_session(&options, &provider)?,
+    };
+    let mut repl = Repl {
+        session,
+        speaker: String::from("user"),
+        provider,
+        tokenizer,
+        local_summarizer: Arc::new(LocalSummarizer::default()),
+        gpt2_generators: Arc::new(gpt2::GeneratorCache::default()),
+    };
+    println!("{}\n{}\n", repl.session, HELP);
+    loop {
+        print!("{}> ", repl.speaker);
+        std::io::stdout().flush()?;
+        let line = match read_line().await? {
+            Some(line) => line,
+            None => break,
+        };
+        if !repl.handle(line.trim()).await {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a line off the blocking pool, `None` once stdin is closed
+async fn read_line() -> std::io::Result<Option<String>> {
+    tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    })
+    .await
+    .expect("Reading stdin panicked")
+}
+
+fn load_session(
+    path: &str,
+    provider: &Arc<dyn CompletionProvider>,
+) -> crate::error::Result<Session> {
+    let saved: storage::SavedSession = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(Session::restore(saved, provider))
+}
+
+impl Repl {
+    /// Handles one line of input, returns `false` when it's time to leave
+    async fn handle(&mut self, line: &str) -> bool {
+        if line.is_empty() {
+            self.reply().await;
+            return true;
+        }
+        if !line.starts_with('/') {
+            self.record(line);
+            return true;
+        }
+        let mut parts = line[1..].splitn(2, ' ');
+        let command = parts.next().unwrap_or_default();
+        let rest = parts.next().unwrap_or_default().trim();
+        match command {
+            "as" if !rest.is_empty() => self.speaker = rest.to_string(),
+            "prompt" => self.show_prompt(),
+            "reply" => self.reply().await,
+            "set" => {
+                let result = parse_set(rest).and_then(|tuning| self.session.tune(tuning, None));
+                match result {
+                    Ok(()) => println!("{}", self.session),
+                    Err(why) => println!("{}", why),
+                }
+            }
+            "undo" => match self.session.undo(&self.tokenizer) {
+                Some(reply) => println!("Took back: {}", reply.text),
+                None => println!("There is no reply to take back"),
+            },
+            "retry" => match self.session.undo(&self.tokenizer) {
+                Some(_) => self.reply().await,
+                None => println!("There is no reply to retry"),
+            },
+            "forget" => match rest.parse() {
+                Ok(count) => {
+                    let forgotten = self.session.forget(count, &self.tokenizer);
+                    println!("Forgot {} lines", forgotten.len());
+                }
+                Err(_) => println!("/forget needs a number of lines"),
+            },
+            "reset" => {
+                self.session.clear();
+                println!("Forgot the conversation");
+            }
+            "save" if !rest.is_empty() => {
+                let saved = serde_json::to_vec_pretty(&self.session.save())
+                    .map_err(crate::error::Error::from)
+                    .and_then(|saved| Ok(fs::write(rest, saved)?));
+                match saved {
+                    Ok(()) => println!("Saved to {}", rest),
+                    Err(why) => println!("Failed to save session: {}", why),
+                }
+            }
+            "load" if !rest.is_empty() => match load_session(rest, &self.provider) {
+                Ok(session) => {
+                    self.session = session;
+                    println!("{}", self.session);
+                }
+                Err(why) => println!("Failed to load session: {}", why),
+            },
+            "quit" | "exit" => return false,
+            _ => println!("{}", HELP),
+        }
+        true
+    }
+
+    fn record(&mut self, text: &str) {
+        let log_item = conversation::LogItem {
+            author_name: Some(self.speaker.clone()),
+            author_nick: None,
+            text: text.to_string(),
+            sent_by_ai: false,
+            message_id: None,
+        };
+        match &mut self.session {
+            Session::GPT2(session) => {
+                if let Err(why) = session.record(log_item, &self.tokenizer) {
+                    println!("Failed to record line: {}", why);
+                }
+            }
+            Session::GPT3(session) => {
+                if let Err(why) = session.record(log_item, &self.tokenizer) {
+                    println!("Failed to record line: {}", why);
+                }
+            }
+        }
+    }
+
+    fn show_prompt(&self) {
+        let prompt = match &self.session {
+            Session::GPT2(session) => session.make_prompt(),
+            Session::GPT3(session) => session.make_prompt(None),
+        };
+        match prompt {
+            Ok(prompt) => println!("---\n{}\n---\n{}", prompt, self.describe_tokens(&prompt)),
+            Err(why) => println!("Failed to build prompt: {}", why),
+        }
+    }
+
+    fn describe_tokens(&self, prompt: &str) -> String {
+        let count = self.tokenizer.count(prompt);
+        match &self.session {
+            Session::GPT2(session) => {
+                format!("{}/{} tokens", count, session.configuration.max_length)
+            }
+            Session::GPT3(session) => format!(
+                "{}/{} tokens",
+                count,
+                session.budget.prompt_tokens(&session.configuration)
+            ),
+        }
+    }
+
+    async fn reply(&mut self) {
+        let reply = match &mut self.session {
+            Session::GPT2(session) => {
+                let payload = gpt2::Payload {
+                    channel_id: REPL_CHANNEL,
+                    generators: Arc::clone(&self.gpt2_generators),
+                    tokenizer: Arc::clone(&self.tokenizer),
+                };
+                session.generate_reply(&payload).await
+            }
+            Session::GPT3(session) => {
+                let payload = gpt3::Payload {
+                    channel_id: REPL_CHANNEL,
+                    tokenizer: Arc::clone(&self.tokenizer),
+                    local_summarizer: Arc::clone(&self.local_summarizer),
+                };
+                session.generate_reply(&payload).await
+            }
+        };
+        match reply {
+            Ok(Some(reply)) => println!("{}: {}", self.session.ai_name(), reply),
+            Ok(None) => println!("(no reply)"),
+            Err(why) => println!("⚠️ {}", why),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_lines_parse_like_the_commands() {
+        assert!(matches!(
+            parse_set("temperature 0.5"),
+            Ok(Tuning::Temperature(temperature)) if (temperature - 0.5).abs() < f64::EPSILON
+        ));
+        assert!(matches!(
+            parse_set("context a quiet library"),
+            Ok(Tuning::Context(context)) if context == "a quiet library"
+        ));
+        assert!(parse_set("temperature 3").is_err(), "Ranges are enforced");
+        assert!(parse_set("volume 11").is_err());
+        assert!(parse_set("").is_err());
+    }
+
+    #[test]
+    fn options_need_their_values() {
+        let options = parse_options(
+            vec!["--engine", "GPT2", "--name", "Ai"]
+                .into_iter()
+                .map(String::from),
+        )
+        .expect("The options are complete");
+        assert_eq!(options.engine, "gpt2");
+        assert_eq!(options.ai_name, "Ai");
+        assert!(parse_options(vec![String::from("--model")].into_iter()).is_err());
+        assert!(parse_options(vec![String::from("--verbose")].into_iter()).is_err());
+    }
+}
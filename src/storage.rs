@@ -0,0 +1,178 @@
+/// This file handles saving sessions (and the state that goes with them) to disk so a restart
+/// doesn't wipe every channel
+use crate::{
+    config::Config, gpt2, gpt3, openai::CompletionProvider, permissions::Permissions,
+    usage::Ledger, ChatTarget, Session,
+};
+use serenity::model::id::{ChannelId, GuildId};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const SESSIONS_DIR: &str = "sessions";
+const PERMISSIONS_FILE: &str = "permissions.json";
+const CONFIG_FILE: &str = "config.toml";
+const USAGE_FILE: &str = "usage.json";
+
+pub struct Storage {
+    root: PathBuf,
+}
+
+/// Engine specific session state, tagged so we know which handler to rebuild
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind")]
+pub enum SavedSession {
+    GPT2(gpt2::SavedSession),
+    GPT3(gpt3::SavedSession),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SessionFile {
+    #[serde(default)]
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    session: SavedSession,
+}
+
+impl Storage {
+    pub fn new(root: impl Into<PathBuf>) -> crate::error::Result<Storage> {
+        let root = root.into();
+        fs::create_dir_all(root.join(SESSIONS_DIR))?;
+        Ok(Storage { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn session_path(&self, chat_target: &ChatTarget) -> PathBuf {
+        let file_name = match chat_target.guild_id {
+            Some(guild_id) => format!("{}-{}.json", guild_id, chat_target.channel_id),
+            None => format!("dm-{}.json", chat_target.channel_id),
+        };
+        self.root.join(SESSIONS_DIR).join(file_name)
+    }
+
+    /// Writes the session for `chat_target`, replacing any previous save
+    pub fn save_session(
+        &self,
+        chat_target: &ChatTarget,
+        session: SavedSession,
+    ) -> crate::error::Result<()> {
+        let file = SessionFile {
+            guild_id: chat_target.guild_id,
+            channel_id: chat_target.channel_id,
+            session,
+        };
+        write_atomically(
+            &self.session_path(chat_target),
+            &serde_json::to_vec_pretty(&file)?,
+        )
+    }
+
+    pub fn remove_session(&self, chat_target: &ChatTarget) -> crate::error::Result<()> {
+        match fs::remove_file(self.session_path(chat_target)) {
+            Err(why) if why.kind() != std::io::ErrorKind::NotFound => Err(why.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads every saved session back. Files that fail to parse are logged and skipped so one
+    /// bad save doesn't keep the bot from starting
+    pub fn load_sessions(
+        &self,
+        completion_provider: &Arc<dyn CompletionProvider>,
+    ) -> crate::error::Result<HashMap<ChatTarget, Session>> {
+        let mut sessions = HashMap::new();
+        for entry in fs::read_dir(self.root.join(SESSIONS_DIR))? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let file: SessionFile = match fs::read(&path)
+                .map_err(crate::error::Error::from)
+                .and_then(|bytes| serde_json::from_slice(&bytes).map_err(Into::into))
+            {
+                Ok(file) => file,
+                Err(why) => {
+                    eprintln!("Failed to load session {}: {}", path.display(), &why);
+                    continue;
+                }
+            };
+            sessions.insert(
+                ChatTarget {
+                    guild_id: file.guild_id,
+                    channel_id: file.channel_id,
+                },
+                Session::restore(file.session, completion_provider),
+            );
+        }
+        Ok(sessions)
+    }
+}
+
+impl Storage {
+    pub fn load_permissions(&self) -> crate::error::Result<Permissions> {
+        self.load_json(PERMISSIONS_FILE)
+    }
+
+    pub fn save_permissions(&self, permissions: &Permissions) -> crate::error::Result<()> {
+        self.save_json(PERMISSIONS_FILE, permissions)
+    }
+
+    pub fn load_usage(&self) -> crate::error::Result<Ledger> {
+        self.load_json(USAGE_FILE)
+    }
+
+    pub fn save_usage(&self, ledger: &Ledger) -> crate::error::Result<()> {
+        self.save_json(USAGE_FILE, ledger)
+    }
+
+    /// The config is written by hand, so it's TOML instead of JSON
+    pub fn load_config(&self) -> crate::error::Result<Config> {
+        match fs::read_to_string(self.root.join(CONFIG_FILE)) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    pub fn save_config(&self, config: &Config) -> crate::error::Result<()> {
+        write_atomically(
+            &self.root.join(CONFIG_FILE),
+            toml::to_string_pretty(config)?.as_bytes(),
+        )
+    }
+
+    /// Reads `file_name` from the data directory, falling back to the default if it was never
+    /// written
+    fn load_json<T>(&self, file_name: &str) -> crate::error::Result<T>
+    where
+        T: serde::de::DeserializeOwned + Default,
+    {
+        match fs::read(self.root.join(file_name)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    fn save_json<T>(&self, file_name: &str, value: &T) -> crate::error::Result<()>
+    where
+        T: serde::Serialize,
+    {
+        write_atomically(&self.root.join(file_name), &serde_json::to_vec_pretty(value)?)
+    }
+}
+
+/// Writes to a sibling temp file first, so a crash mid-write never leaves a truncated save
+fn write_atomically(path: &Path, contents: &[u8]) -> crate::error::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,288 @@
+/// This file is an in-process GPT2/GPT3 byte pair encoder, so counting tokens never needs a
+/// network round trip. GPT2 and the GPT3 engines share the same vocabulary and merges
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+};
+
+/// Finished merges kept around before the cache starts over. Chat vocabulary is small, so this
+/// covers the words that matter without growing with every typo ever seen
+const CACHE_CAPACITY: usize = 10_000;
+
+pub struct Tokenizer {
+    encoder: HashMap<String, u32>,
+    decoder: HashMap<u32, String>,
+    bpe_ranks: HashMap<(String, String), usize>,
+    byte_encoder: Vec<char>,
+    /// Words repeat a lot in chat, so finished merges are kept around
+    cache: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl Tokenizer {
+    /// Loads `vocab.json` and `merges.txt` from `dir`
+    pub fn from_dir(dir: impl AsRef<Path>) -> crate::error::Result<Tokenizer> {
+        let dir = dir.as_ref();
+        let encoder = serde_json::from_slice(&fs::read(dir.join("vocab.json"))?)?;
+        let merges = fs::read_to_string(dir.join("merges.txt"))?;
+        Ok(Tokenizer::new(encoder, &merges))
+    }
+
+    /// `merges` is in the `merges.txt` format: an optional `#version` header followed by one
+    /// space separated pair per line, highest priority first
+    pub fn new(encoder: HashMap<String, u32>, merges: &str) -> Tokenizer {
+        let bpe_ranks = merges
+            .lines()
+            .filter(|line| !line.starts_with("#version"))
+            .filter_map(|line| {
+                let mut parts = line.split(' ');
+                match (parts.next(), parts.next()) {
+                    (Some(first), Some(second)) => Some((first.to_string(), second.to_string())),
+                    _ => None,
+                }
+            })
+            .enumerate()
+            .map(|(rank, pair)| (pair, rank))
+            .collect();
+        let decoder = encoder
+            .iter()
+            .map(|(token, id)| (*id, token.clone()))
+            .collect();
+        Tokenizer {
+            encoder,
+            decoder,
+            bpe_ranks,
+            byte_encoder: bytes_to_unicode(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.tokenize(text).len()
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.tokenize(text)
+            .iter()
+            .filter_map(|token| self.encoder.get(token).copied())
+            .collect()
+    }
+
+    /// Turns token ids back into text. Ids that aren't in the vocabulary are skipped
+    pub fn decode(&self, ids: &[u32]) -> String {
+        let bytes: Vec<u8> = ids
+            .iter()
+            .filter_map(|id| self.decoder.get(id))
+            .flat_map(|token| token.chars())
+            .filter_map(|c| self.byte_encoder.iter().position(|encoded| *encoded == c))
+            .map(|byte| byte as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for piece in pre_tokenize(text) {
+            let encoded: String = piece
+                .bytes()
+                .map(|byte| self.byte_encoder[byte as usize])
+                .collect();
+            tokens.extend(self.bpe(encoded));
+        }
+        tokens
+    }
+
+    fn bpe(&self, token: String) -> Vec<String> {
+        if let Some(cached) = self.cache.lock().expect("BPE cache poisoned").get(&token) {
+            return cached.clone();
+        }
+        let word = self.merge(&token);
+        let mut cache = self.cache.lock().expect("BPE cache poisoned");
+        // dropping everything is crude, but the words that matter are back in no time
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(token, word.clone());
+        word
+    }
+
+    fn merge(&self, token: &str) -> Vec<String> {
+        let mut word: Vec<String> = token.chars().map(|c| c.to_string()).collect();
+        while word.len() > 1 {
+            let best_pair = word
+                .windows(2)
+                .filter_map(|pair| {
+                    self.bpe_ranks
+                        .get(&(pair[0].clone(), pair[1].clone()))
+                        .map(|rank| (rank, pair))
+                })
+                .min_by_key(|(rank, _)| *rank)
+                .map(|(_, pair)| (pair[0].clone(), pair[1].clone()));
+            let (first, second) = match best_pair {
+                Some(pair) => pair,
+                None => break,
+            };
+            let mut merged = Vec::with_capacity(word.len());
+            let mut index = 0;
+            while index < word.len() {
+                if index + 1 < word.len() && word[index] == first && word[index + 1] == second {
+                    merged.push(format!("{}{}", first, second));
+                    index += 2;
+                } else {
+                    merged.push(word[index].clone());
+                    index += 1;
+                }
+            }
+            word = merged;
+        }
+        word
+    }
+}
+
+/// GPT2's reversible mapping from bytes to printable characters, so the merges never have to deal
+/// with whitespace or control characters
+fn bytes_to_unicode() -> Vec<char> {
+    let printable = |byte: u32| {
+        (u32::from(b'!')..=u32::from(b'~')).contains(&byte)
+            || (0xA1..=0xAC).contains(&byte)
+            || (0xAE..=0xFF).contains(&byte)
+    };
+    let mut shifted = 0;
+    (0..256_u32)
+        .map(|byte| {
+            let code_point = if printable(byte) {
+                byte
+            } else {
+                shifted += 1;
+                255 + shifted
+            };
+            std::char::from_u32(code_point).expect("GPT2 byte mapping is always valid")
+        })
+        .collect()
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Letter,
+    Number,
+    Whitespace,
+    Other,
+}
+
+impl CharClass {
+    fn of(c: char) -> CharClass {
+        if c.is_alphabetic() {
+            CharClass::Letter
+        } else if c.is_numeric() {
+            CharClass::Number
+        } else if c.is_whitespace() {
+            CharClass::Whitespace
+        } else {
+            CharClass::Other
+        }
+    }
+}
+
+const CONTRACTIONS: [&str; 7] = ["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+
+/// Splits text the same way as GPT2's
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+` pattern
+fn pre_tokenize(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let offset = |index: usize| chars.get(index).map_or(text.len(), |(offset, _)| *offset);
+    let mut pieces = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let start = index;
+        let c = chars[index].1;
+        if c == '\'' {
+            if let Some(contraction) = CONTRACTIONS
+                .iter()
+                .find(|contraction| text[offset(index)..].starts_with(*contraction))
+            {
+                index += contraction.len();
+                pieces.push(&text[offset(start)..offset(index)]);
+                continue;
+            }
+        }
+        if c.is_whitespace() {
+            let mut end = index;
+            while end < chars.len() && chars[end].1.is_whitespace() {
+                end += 1;
+            }
+            if end == chars.len() {
+                index = end;
+            } else if end - index > 1 {
+                // the last whitespace character belongs to the next piece
+                index = end - 1;
+            } else if c != ' ' {
+                index = end;
+            }
+            if index > start {
+                pieces.push(&text[offset(start)..offset(index)]);
+                continue;
+            }
+        }
+        // a single leading space is attached to the following run
+        if c == ' ' {
+            index += 1;
+        }
+        let class = CharClass::of(chars[index].1);
+        while index < chars.len() && CharClass::of(chars[index].1) == class {
+            index += 1;
+        }
+        pieces.push(&text[offset(start)..offset(index)]);
+    }
+    pieces
+}
+
+#[cfg(test)]
+pub fn test_tokenizer() -> Tokenizer {
+    let encoder = ["h", "e", "l", "o", "he", "ll", "hell", "hello", "Ġ", "w", "Ġw"]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+    Tokenizer::new(encoder, "#version: 0.2\nh e\nl l\nhe ll\nhell o\nĠ w\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_tokenize_matches_gpt2_pattern() {
+        assert_eq!(
+            pre_tokenize("I'm  here!\n"),
+            vec!["I", "'m", " ", " here", "!", "\n"]
+        );
+        assert_eq!(pre_tokenize("a\n\nb  "), vec!["a", "\n", "\n", "b", "  "]);
+        assert_eq!(
+            pre_tokenize("'hello, it's 42"),
+            vec!["'", "hello", ",", " it", "'s", " 42"]
+        );
+    }
+
+    #[test]
+    fn merges_by_rank() {
+        let tokenizer = test_tokenizer();
+        assert_eq!(
+            tokenizer.tokenize("hello world"),
+            vec!["hello", "Ġw", "o", "r", "l", "d"]
+        );
+        assert_eq!(tokenizer.count("hello world"), 6);
+        assert_eq!(tokenizer.encode("hello"), vec![7]);
+        assert_eq!(tokenizer.decode(&tokenizer.encode("hello wo")), "hello wo");
+    }
+
+    #[test]
+    fn cache_stays_bounded() {
+        let tokenizer = test_tokenizer();
+        for word in 0..CACHE_CAPACITY + 10 {
+            tokenizer.count(&format!("w{}", word));
+        }
+        assert!(tokenizer.cache.lock().unwrap().len() <= CACHE_CAPACITY);
+        assert_eq!(tokenizer.tokenize("hello"), vec!["hello"]);
+    }
+}
@@ -1,15 +1,32 @@
 use super::LogTransformer;
-use crate::gpt3::CompletionParameters;
+use crate::{
+    gpt2,
+    gpt3::CompletionParameters,
+    openai::{ChatMessage, Role},
+};
+use serenity::model::id::MessageId;
+use std::fmt::Write;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct LogItem {
     pub author_name: Option<String>,
     pub author_nick: Option<String>,
     pub text: String,
     pub sent_by_ai: bool,
+    /// The chat message this line came from, or went out as
+    #[serde(default)]
+    pub message_id: Option<MessageId>,
 }
 
 impl LogItem {
+    /// Nickname if they have one, falling back to their username
+    pub fn speaker_name(&self) -> &str {
+        self.author_nick
+            .as_deref()
+            .or_else(|| self.author_name.as_deref())
+            .unwrap_or("Somebody")
+    }
+
     pub fn user_identifier(&self) -> String {
         if let Some(ref nick) = self.author_nick {
             format!("User ({})", nick)
@@ -21,10 +38,13 @@ impl LogItem {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Transformer {
     pub ai_name: String,
     pub context: Option<String>,
+    /// Condensed version of log lines that were trimmed to stay under the token budget
+    #[serde(default)]
+    pub summary: Option<String>,
 }
 
 impl LogTransformer for Transformer {
@@ -46,7 +66,14 @@ impl LogTransformer for Transformer {
             ..CompletionParameters::default()
         }
     }
-    fn default_gpt2_configuration(&self) -> crate::gpt2::Configuration {}
+    fn default_gpt2_configuration(&self) -> gpt2::Configuration {
+        gpt2::Configuration {
+            temperature: 0.9_f64,
+            top_p: 1.0_f64,
+            repetition_penalty: 1.2_f64,
+            ..gpt2::Configuration::default()
+        }
+    }
 
     fn on_ai_line_observed(&mut self, _line: &str) {}
     fn on_human_line_observed(&mut self, _line: &str) {}
@@ -59,9 +86,35 @@ impl LogTransformer for Transformer {
         if let Some(ref ctx) = self.context {
             write!(buf, "{}\n\n", ctx)?;
         }
+        if let Some(ref summary) = self.summary {
+            write!(buf, "Earlier in the conversation: {}\n\n", summary)?;
+        }
         Ok(())
     }
 
+    /// The context and summary go into the system message, everyone but the AI is the user role
+    fn chat_messages(&self, log: &[LogItem]) -> Result<Vec<ChatMessage>, std::fmt::Error> {
+        let mut system = String::new();
+        self.prepare(&mut system)?;
+        write!(
+            system,
+            "You are {name}, chatting with the people below. Reply with a single message as {name}.",
+            name = self.ai_name
+        )?;
+        let mut messages = vec![ChatMessage::new(Role::System, system)];
+        messages.extend(log.iter().map(|log_item| {
+            if log_item.sent_by_ai {
+                ChatMessage::new(Role::Assistant, log_item.text.clone())
+            } else {
+                ChatMessage::new(
+                    Role::User,
+                    format!("{}: {}", log_item.speaker_name(), log_item.text),
+                )
+            }
+        }));
+        Ok(messages)
+    }
+
     fn transform(&self, mut buf: impl std::fmt::Write, log_item: &LogItem) -> std::fmt::Result {
         // TODO(haze): wasted space here
         let user_identifier = log_item.user_identifier();
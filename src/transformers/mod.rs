@@ -1,11 +1,10 @@
 pub mod conversation;
-use crate::{gpt2, gpt3};
+use crate::{gpt2, gpt3, openai::ChatMessage};
 use conversation::LogItem;
-use gpt3::CompletionParameters;
 
 pub trait LogTransformer {
-    fn default_configuration(&self) -> CompletionParameters;
-    fn default_gpt2_configuration(&self) -> CompletionParameters;
+    fn default_gpt3_configuration(&self) -> gpt3::CompletionParameters;
+    fn default_gpt2_configuration(&self) -> gpt2::Configuration;
     fn on_human_line_observed(&mut self, line: &str);
     fn on_ai_line_observed(&mut self, line: &str);
 
@@ -14,22 +13,42 @@ pub trait LogTransformer {
     fn append_prompt(&self, buf: impl std::fmt::Write) -> std::fmt::Result;
     fn prepare(&self, buf: impl std::fmt::Write) -> std::fmt::Result;
     fn transform(&self, buf: impl std::fmt::Write, log_item: &LogItem) -> std::fmt::Result;
+    /// The log as role messages, for chat completions
+    fn chat_messages(&self, log: &[LogItem]) -> Result<Vec<ChatMessage>, std::fmt::Error>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub enum TransformerKind {
     Conversation(conversation::Transformer),
 }
 
 impl TransformerKind {
+    pub fn get_ai_name(&self) -> &str {
+        match self {
+            TransformerKind::Conversation(convo) => &convo.ai_name,
+        }
+    }
     pub fn get_context(&self) -> &Option<String> {
         match self {
             TransformerKind::Conversation(convo) => &convo.context,
         }
     }
+    /// Sets the context, filling in `{name}` with the AI's name like `enable` does
     pub fn set_context(&mut self, context: &str) {
         match self {
-            TransformerKind::Conversation(convo) => convo.context = Some(context.to_string()),
+            TransformerKind::Conversation(convo) => {
+                convo.context = Some(context.replace("{name}", &*convo.ai_name))
+            }
+        }
+    }
+    pub fn get_summary(&self) -> &Option<String> {
+        match self {
+            TransformerKind::Conversation(convo) => &convo.summary,
+        }
+    }
+    pub fn set_summary(&mut self, summary: Option<String>) {
+        match self {
+            TransformerKind::Conversation(convo) => convo.summary = summary,
         }
     }
     pub fn default_gpt2_configuration(&self) -> gpt2::Configuration {
@@ -46,28 +65,91 @@ impl TransformerKind {
         .default_gpt3_configuration()
     }
 
-    fn prepare(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
+    pub fn prepare(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
         match self {
             TransformerKind::Conversation(trans) => trans,
         }
         .prepare(buf)
     }
-    fn transform(&self, buf: impl std::fmt::Write, log_item: &LogItem) -> std::fmt::Result {
+    pub fn transform(&self, buf: impl std::fmt::Write, log_item: &LogItem) -> std::fmt::Result {
         match self {
             TransformerKind::Conversation(trans) => trans,
         }
         .transform(buf, log_item)
     }
-    fn append_prompt(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
+    pub fn chat_messages(&self, log: &[LogItem]) -> Result<Vec<ChatMessage>, std::fmt::Error> {
+        match self {
+            TransformerKind::Conversation(trans) => trans,
+        }
+        .chat_messages(log)
+    }
+    pub fn append_prompt(&self, buf: impl std::fmt::Write) -> std::fmt::Result {
         match self {
             TransformerKind::Conversation(trans) => trans,
         }
         .append_prompt(buf)
     }
-    fn get_stop_params(&self) -> Option<Vec<String>> {
+    pub fn get_stop_params(&self) -> Option<Vec<String>> {
         match self {
             TransformerKind::Conversation(trans) => trans,
         }
         .stop_tokens()
     }
 }
+
+/// Parses `<engine> <transformer> [transformer args...]` from an enable command. The engine is
+/// `None` when `default` was given, so each engine can pick its own default
+pub async fn log_transformer_from_serenity_args(
+    context: &serenity::prelude::Context,
+    args: &mut serenity::framework::standard::Args,
+) -> Result<(TransformerKind, Option<String>), crate::commands::StringError> {
+    // 0th, the engine
+    let engine = {
+        if let Ok(arg) = args.single::<String>() {
+            match &*arg.to_lowercase() {
+                "default" => None,
+                _ => Some(arg),
+            }
+        } else {
+            return Err("Missing engine (if you aren't sure, use `default`)".into());
+        }
+    };
+    // first, the transform type
+    let transform_type = args.single::<String>()?.to_lowercase();
+    Ok(match &*transform_type {
+        // if we get single, then get the ai name (otherwise, default to the bots name)
+        "conversation" | "convo" => {
+            let bot_name = context.http.get_current_application_info().await?.name;
+            let ai_name = {
+                let temp = args
+                    .single_quoted()
+                    // it's ok to do this since I don't expet this method to be called frequently
+                    .unwrap_or_else(|_| bot_name.clone());
+                if temp == "_" {
+                    bot_name
+                } else {
+                    temp
+                }
+            };
+            let context = {
+                let rest = args.rest();
+                let trimmed = rest.trim().trim_matches('`').trim_matches('"').trim();
+                if trimmed.is_empty() {
+                    None
+                } else {
+                    Some(trimmed.to_string())
+                }
+            }
+            .map(|context| context.replace("{name}", &*ai_name));
+            (
+                TransformerKind::Conversation(conversation::Transformer {
+                    ai_name,
+                    context,
+                    summary: None,
+                }),
+                engine,
+            )
+        }
+        _ => return Err("Invalid conversation type".into()),
+    })
+}
@@ -0,0 +1,345 @@
+/// This file keeps count of the tokens every guild (and everyone DMing the bot) spends on
+/// completions, so owners can see where the bill comes from and cap it
+use crate::{config::EngineRules, ChatTarget};
+use serenity::model::id::{GuildId, UserId};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Requests and tokens spent on them. Tokens are counted with our own tokenizer, so they can be
+/// a little off from what the API bills
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Tally {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Tally {
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: &Tally) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tokens ({} prompt, {} completion) in {} requests",
+            self.tokens(),
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.requests
+        )
+    }
+}
+
+/// A day (or month) of spending, broken down by channel and by the user who asked for each reply
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Period {
+    /// `2020-10-17` for days, `2020-10` for months
+    pub key: String,
+    pub total: Tally,
+    pub channels: HashMap<u64, Tally>,
+    pub users: HashMap<u64, Tally>,
+    /// Channels that were told this period's quota is used up
+    notified: HashSet<u64>,
+}
+
+impl Period {
+    fn new(key: String) -> Period {
+        Period {
+            key,
+            ..Period::default()
+        }
+    }
+
+    fn record(&mut self, chat_target: &ChatTarget, user_id: UserId, spent: &Tally) {
+        self.total.add(spent);
+        self.channels
+            .entry(chat_target.channel_id.0)
+            .or_default()
+            .add(spent);
+        self.users.entry(user_id.0).or_default().add(spent);
+    }
+
+    /// The `count` users who spent the most, biggest spender first
+    pub fn top_users(&self, count: usize) -> Vec<(UserId, Tally)> {
+        let mut users: Vec<_> = self
+            .users
+            .iter()
+            .map(|(user_id, tally)| (UserId(*user_id), *tally))
+            .collect();
+        users.sort_by(|(_, a), (_, b)| b.tokens().cmp(&a.tokens()));
+        users.truncate(count);
+        users
+    }
+}
+
+/// Spending for one guild, or for one user's DMs
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Account {
+    day: Period,
+    month: Period,
+    pub all_time: Tally,
+}
+
+impl Account {
+    /// Today's spending, if there was any
+    pub fn day(&self, today: Date) -> Option<&Period> {
+        Some(&self.day).filter(|day| day.key == today.day_key())
+    }
+
+    /// This month's spending, if there was any
+    pub fn month(&self, today: Date) -> Option<&Period> {
+        Some(&self.month).filter(|month| month.key == today.month_key())
+    }
+
+    fn record(&mut self, chat_target: &ChatTarget, user_id: UserId, spent: &Tally, today: Date) {
+        if self.day.key != today.day_key() {
+            self.day = Period::new(today.day_key());
+        }
+        if self.month.key != today.month_key() {
+            self.month = Period::new(today.month_key());
+        }
+        self.day.record(chat_target, user_id, spent);
+        self.month.record(chat_target, user_id, spent);
+        self.all_time.add(spent);
+    }
+
+    fn period_mut(&mut self, exceeded: &QuotaExceeded) -> &mut Period {
+        match exceeded {
+            QuotaExceeded::Daily(_) => &mut self.day,
+            QuotaExceeded::Monthly(_) => &mut self.month,
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Ledger {
+    guilds: HashMap<u64, Account>,
+    /// Keyed by user, so nobody can use up the quota for everyone else's DMs
+    direct_message_users: HashMap<u64, Account>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum QuotaExceeded {
+    #[error("The daily quota of {0} tokens is used up, replies are back tomorrow (UTC)")]
+    Daily(u64),
+    #[error("The monthly quota of {0} tokens is used up, replies are back next month")]
+    Monthly(u64),
+}
+
+impl Ledger {
+    /// The guild's account, or `user_id`'s for DMs
+    pub fn account(&self, guild_id: Option<GuildId>, user_id: UserId) -> Option<&Account> {
+        match guild_id {
+            Some(guild_id) => self.guilds.get(&guild_id.0),
+            None => self.direct_message_users.get(&user_id.0),
+        }
+    }
+
+    fn account_mut(&mut self, guild_id: Option<GuildId>, user_id: UserId) -> &mut Account {
+        match guild_id {
+            Some(guild_id) => self.guilds.entry(guild_id.0).or_default(),
+            None => self.direct_message_users.entry(user_id.0).or_default(),
+        }
+    }
+
+    /// Adds what a reply in `chat_target` cost, on behalf of `user_id`
+    pub fn record(
+        &mut self,
+        chat_target: &ChatTarget,
+        user_id: UserId,
+        spent: &Tally,
+        today: Date,
+    ) {
+        self.account_mut(chat_target.guild_id, user_id)
+            .record(chat_target, user_id, spent, today);
+    }
+
+    /// Fails once the guild (or `user_id`, in DMs) spent as much as `rules` allow for today or
+    /// this month
+    pub fn check_quota(
+        &self,
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+        rules: &EngineRules,
+        today: Date,
+    ) -> Result<(), QuotaExceeded> {
+        let account = match self.account(guild_id, user_id) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let spent = |period: Option<&Period>| period.map_or(0, |period| period.total.tokens());
+        if let Some(quota) = rules.daily_token_quota {
+            if spent(account.day(today)) >= quota {
+                return Err(QuotaExceeded::Daily(quota));
+            }
+        }
+        if let Some(quota) = rules.monthly_token_quota {
+            if spent(account.month(today)) >= quota {
+                return Err(QuotaExceeded::Monthly(quota));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `chat_target` still has to be told about `exceeded`, which it only is once per day
+    /// (or month)
+    pub fn should_notify(
+        &mut self,
+        chat_target: &ChatTarget,
+        user_id: UserId,
+        exceeded: &QuotaExceeded,
+    ) -> bool {
+        self.account_mut(chat_target.guild_id, user_id)
+            .period_mut(exceeded)
+            .notified
+            .insert(chat_target.channel_id.0)
+    }
+}
+
+/// A calendar day in UTC, which is when quotas roll over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn today() -> Date {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        Date::from_days_since_epoch((seconds / 86_400) as i64)
+    }
+
+    /// Howard Hinnant's `civil_from_days`, which saves pulling in a date crate for this
+    fn from_days_since_epoch(days: i64) -> Date {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        // months counted from March, so the leap day is the last day of the year
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        Date {
+            year: year_of_era + era * 400 + (month <= 2) as i64,
+            month: month as u32,
+            day: day as u32,
+        }
+    }
+
+    fn day_key(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    fn month_key(&self) -> String {
+        format!("{:04}-{:02}", self.year, self.month)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::model::id::ChannelId;
+
+    #[test]
+    fn dates_are_counted_from_the_epoch() {
+        let date = |days| Date::from_days_since_epoch(days).day_key();
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(-1), "1969-12-31");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(18_552), "2020-10-17");
+    }
+
+    #[test]
+    fn quotas_apply_per_guild_and_roll_over() {
+        let guild = ChatTarget {
+            guild_id: Some(GuildId(1)),
+            channel_id: ChannelId(2),
+        };
+        let rules = EngineRules {
+            daily_token_quota: Some(100),
+            monthly_token_quota: Some(150),
+            ..EngineRules::default()
+        };
+        let spent = Tally {
+            requests: 1,
+            prompt_tokens: 80,
+            completion_tokens: 20,
+        };
+        let day = Date::from_days_since_epoch(18_552);
+        let mut ledger = Ledger::default();
+        ledger.record(&guild, UserId(10), &spent, day);
+
+        let exceeded = ledger
+            .check_quota(guild.guild_id, UserId(11), &rules, day)
+            .expect_err("The day's tokens are spent");
+        assert!(matches!(exceeded, QuotaExceeded::Daily(100)));
+        assert!(ledger.should_notify(&guild, UserId(11), &exceeded));
+        assert!(!ledger.should_notify(&guild, UserId(10), &exceeded));
+        assert!(ledger.check_quota(None, UserId(10), &rules, day).is_ok());
+        let next_day = Date::from_days_since_epoch(18_553);
+        assert!(ledger
+            .check_quota(guild.guild_id, UserId(10), &rules, next_day)
+            .is_ok());
+
+        ledger.record(&guild, UserId(11), &spent, next_day);
+        assert!(matches!(
+            ledger.check_quota(guild.guild_id, UserId(10), &rules, next_day),
+            Err(QuotaExceeded::Monthly(150))
+        ));
+        let month = ledger
+            .account(guild.guild_id, UserId(10))
+            .and_then(|account| account.month(next_day))
+            .expect("Both days are in October");
+        assert_eq!(month.total.requests, 2);
+        assert_eq!(month.channels[&2].tokens(), 200);
+        assert_eq!(month.top_users(1).len(), 1);
+    }
+
+    #[test]
+    fn direct_messages_are_counted_per_user() {
+        let dm = ChatTarget {
+            guild_id: None,
+            channel_id: ChannelId(3),
+        };
+        let rules = EngineRules {
+            daily_token_quota: Some(100),
+            ..EngineRules::default()
+        };
+        let spent = Tally {
+            requests: 1,
+            prompt_tokens: 100,
+            completion_tokens: 0,
+        };
+        let day = Date::from_days_since_epoch(18_552);
+        let mut ledger = Ledger::default();
+        ledger.record(&dm, UserId(10), &spent, day);
+        assert!(ledger.check_quota(None, UserId(10), &rules, day).is_err());
+        assert!(ledger.check_quota(None, UserId(11), &rules, day).is_ok());
+        assert!(ledger.account(None, UserId(11)).is_none());
+    }
+}